@@ -0,0 +1,913 @@
+//! Assembly of the EPUB out of the saved chapters, and checks of an existing one
+
+use crate::{
+    error::NovelError,
+    event::Event,
+    novel::{sanitize_filename, Novel, IMAGES_DIR},
+    pages::title_volume,
+};
+use chrono::{Datelike, Timelike, Utc};
+use epub_builder::{EpubBuilder, EpubContent, EpubVersion, ReferenceType, TocElement, ZipLibrary};
+use html_builder::{Buffer, Html5};
+use regex::Regex;
+use scraper::Html;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    io::{Cursor, Read, Seek, Write as OWrite},
+    path::PathBuf,
+};
+
+const TITLE_PAGE: &str = "title.xhtml";
+
+pub(crate) fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// Path of the stylesheet inside the EPUB, `EpubBuilder::stylesheet` puts it there
+pub(crate) const STYLESHEET: &str = "stylesheet.css";
+
+const DEFAULT_CSS: &str = "body { margin: 0 5%; line-height: 1.5; }
+h1 { text-align: center; margin: 1em 0; }
+p { margin: 0.5em 0; text-indent: 1.5em; }
+p.author, p.status, p.source { text-align: center; text-indent: 0; }
+div.description { margin: 2em 0; font-style: italic; }
+hr.scene-break { border: 0; border-top: 1px solid; width: 30%; margin: 1.5em auto; }
+ol.endnotes { font-size: 0.9em; }
+";
+
+// What is percent-encoded in the hrefs `epub_builder` writes into the XML as they are,
+// everything but ASCII too
+const HREF: &percent_encoding::AsciiSet =
+    &percent_encoding::CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'&').add(b'<').add(b'>').add(b'?').add(b'\'');
+
+/// A suggestion for `EpubOptions::extras`, titles of side stories and bonus chapters.
+/// Nothing is labeled unless asked for, a main line chapter can match it too
+pub const EXTRA_CHAPTERS: &str = r#"(?i)\b(side[ -]?story|bonus|extra)\b"#;
+
+// Frequent short words telling apart the languages written in the Latin script
+const STOPWORDS: [(&str, [&str; 8]); 7] = [
+    ("en", ["the", "and", "of", "to", "was", "he", "she", "that"]),
+    ("es", ["el", "los", "que", "y", "del", "las", "se", "por"]),
+    ("fr", ["le", "les", "et", "des", "est", "une", "pas", "je"]),
+    ("de", ["der", "die", "und", "das", "ist", "nicht", "ich", "zu"]),
+    ("pt", ["os", "não", "uma", "com", "ele", "ela", "mas", "em"]),
+    ("it", ["il", "che", "di", "non", "è", "un", "per", "gli"]),
+    ("id", ["yang", "dan", "itu", "ini", "tidak", "dengan", "aku", "dia"]),
+];
+
+/// Best guess at the language code of `text`, by its script and for the Latin one its common words
+fn detect_language(text: &str) -> Option<&'static str> {
+    let mut scripts: HashMap<&str, usize> = HashMap::new();
+    let mut letters = 0;
+    for c in text.chars().filter(|c| c.is_alphabetic()) {
+        letters += 1;
+        let script = match c as u32 {
+            0x3040..=0x30ff => "ja",
+            0xac00..=0xd7af | 0x1100..=0x11ff => "ko",
+            0x4e00..=0x9fff | 0x3400..=0x4dbf => "zh",
+            0x0400..=0x04ff => "ru",
+            0x0e00..=0x0e7f => "th",
+            0x0600..=0x06ff => "ar",
+            0x0370..=0x03ff => "el",
+            0x0590..=0x05ff => "he",
+            0x0900..=0x097f => "hi",
+            _ => "latin",
+        };
+        *scripts.entry(script).or_default() += 1;
+    }
+    // Japanese mixes kanji with kana, a little kana is enough to tell it from Chinese
+    if scripts.get("ja").is_some_and(|&kana| kana * 20 >= letters) {
+        return Some("ja");
+    }
+    let (script, _) = scripts.into_iter().max_by_key(|(_, count)| *count)?;
+    if script != "latin" {
+        return Some(script);
+    }
+    let words: Vec<String> = text.split(|c: char| !c.is_alphabetic()).map(str::to_lowercase).collect();
+    STOPWORDS
+        .iter()
+        .map(|(lang, stopwords)| (lang, words.iter().filter(|word| stopwords.contains(&word.as_str())).count()))
+        .filter(|(_, hits)| *hits > 0)
+        .max_by_key(|(_, hits)| *hits)
+        .map(|(lang, _)| *lang)
+}
+
+const FILENAME_PLACEHOLDERS: [&str; 3] = ["title", "author", "series"];
+
+pub fn validate_filename_template(template: &str) -> Result<(), Box<dyn std::error::Error>> {
+    for captures in Regex::new(r#"\{([^}]*)\}"#)?.captures_iter(template) {
+        if !FILENAME_PLACEHOLDERS.contains(&&captures[1]) {
+            return Err(Box::new(NovelError::Other(format!(
+                "Unknown placeholder '{}' in '{template}', use {}",
+                &captures[0],
+                FILENAME_PLACEHOLDERS.map(|name| format!("{{{name}}}")).join(", ")
+            ))));
+        }
+    }
+    Ok(())
+}
+
+// In one pass, so a title that happens to contain "{author}" stays as it is
+fn render_filename(template: &str, values: &[(&str, String)]) -> Result<String, Box<dyn std::error::Error>> {
+    let placeholder = Regex::new(r#"\{([^}]*)\}"#)?;
+    Ok(placeholder
+        .replace_all(template, |captures: &regex::Captures| {
+            values
+                .iter()
+                .find(|(key, _)| *key == &captures[1])
+                .map_or(captures[0].to_string(), |(_, value)| value.clone())
+        })
+        .to_string())
+}
+
+/// Minimal zip writer, the `zip` crate is built without a compression backend
+/// so the archive produced by `epub_builder` is stored uncompressed.
+struct EpubZip<W: OWrite> {
+    out: W,
+    level: u8,
+    // The time stamp of every entry
+    modified: chrono::NaiveDateTime,
+    offset: u32,
+    central: Vec<u8>,
+    count: u16,
+}
+
+impl<W: OWrite> EpubZip<W> {
+    fn new(out: W, level: u8) -> Self {
+        Self { out, level, modified: chrono::Local::now().naive_local(), offset: 0, central: vec![], count: 0 }
+    }
+
+    fn add(&mut self, name: &str, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        // The mimetype has to stay stored so readers can sniff it at a fixed offset
+        let (method, body): (u16, Cow<[u8]>) = if self.level == 0 || name == "mimetype" {
+            (0, Cow::Borrowed(data))
+        } else {
+            (8, Cow::Owned(miniz_oxide::deflate::compress_to_vec(data, self.level)))
+        };
+        let crc = crc32fast::hash(data);
+        let now = self.modified;
+        let time = ((now.hour() << 11) | (now.minute() << 5) | (now.second() / 2)) as u16;
+        let date = (((now.year() - 1980) << 9) as u32 | (now.month() << 5) | now.day()) as u16;
+
+        // Bit 11 marks the name as UTF-8, readers take it as CP437 otherwise
+        let flags: u16 = if name.is_ascii() { 0 } else { 0x0800 };
+
+        let mut common = vec![];
+        common.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        common.extend_from_slice(&flags.to_le_bytes());
+        common.extend_from_slice(&method.to_le_bytes());
+        common.extend_from_slice(&time.to_le_bytes());
+        common.extend_from_slice(&date.to_le_bytes());
+        common.extend_from_slice(&crc.to_le_bytes());
+        common.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes()); // extra field length
+
+        let mut local = vec![];
+        local.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        local.extend_from_slice(&common);
+        local.extend_from_slice(name.as_bytes());
+        self.out.write_all(&local)?;
+        self.out.write_all(&body)?;
+
+        self.central.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        self.central.extend_from_slice(&20u16.to_le_bytes()); // version made by
+        self.central.extend_from_slice(&common);
+        self.central.extend_from_slice(&0u16.to_le_bytes()); // comment length
+        self.central.extend_from_slice(&0u16.to_le_bytes()); // disk number
+        self.central.extend_from_slice(&0u16.to_le_bytes()); // internal attributes
+        self.central.extend_from_slice(&0u32.to_le_bytes()); // external attributes
+        self.central.extend_from_slice(&self.offset.to_le_bytes());
+        self.central.extend_from_slice(name.as_bytes());
+
+        self.offset += (local.len() + body.len()) as u32;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<W, Box<dyn std::error::Error>> {
+        self.out.write_all(&self.central)?;
+        let mut end = vec![];
+        end.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes()); // disk number
+        end.extend_from_slice(&0u16.to_le_bytes()); // disk with the central directory
+        end.extend_from_slice(&self.count.to_le_bytes());
+        end.extend_from_slice(&self.count.to_le_bytes());
+        end.extend_from_slice(&(self.central.len() as u32).to_le_bytes());
+        end.extend_from_slice(&self.offset.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes()); // comment length
+        self.out.write_all(&end)?;
+        Ok(self.out)
+    }
+}
+
+// The archive generated by `epub_builder` before its repacking, removed however the build ends
+struct ScratchFile(String);
+
+impl Drop for ScratchFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Rewrites the archive generated by `epub_builder` with the requested compression.
+/// `epub_builder` only accepts a fixed set of metadata keys, everything else is
+/// patched into the generated `content.opf` on the way.
+/// Entries are copied one at a time, the repacked book is written out as it goes.
+fn repack_epub<R: Read + Seek, W: OWrite>(
+    epub: R,
+    mut writer: EpubZip<W>,
+    patch_opf: impl Fn(&str) -> Result<String, Box<dyn std::error::Error>>,
+) -> Result<W, Box<dyn std::error::Error>> {
+    let mut archive = zip::ZipArchive::new(epub)?;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let name = file.name().to_string();
+        // Stored under the name the href resolves to, see `Novel::epub_href`
+        let name = percent_encoding::percent_decode_str(&name).decode_utf8_lossy().to_string();
+        let mut data = vec![];
+        std::io::copy(&mut file, &mut data)?;
+        if name == "OEBPS/content.opf" {
+            data = patch_opf(&String::from_utf8(data)?)?.into_bytes();
+        }
+        // `epub_builder` puts the titles of the TOC entries without children and of the landmarks in as they are
+        if name == "OEBPS/nav.xhtml" || name == "OEBPS/toc.xhtml" {
+            data = escape_bare_ampersands(&String::from_utf8(data)?).into_bytes();
+        }
+        writer.add(&name, &data)?;
+    }
+    writer.finish()
+}
+
+/// Escapes every `&` that does not start a predefined entity or a character reference
+fn escape_bare_ampersands(xml: &str) -> String {
+    let re = Regex::new(r"&((?:amp|lt|gt|quot|apos|#[0-9]+|#x[0-9a-fA-F]+);)?").unwrap();
+    re.replace_all(xml, |caps: &regex::Captures| match caps.get(1) {
+        Some(_) => caps[0].to_string(),
+        None => "&amp;".to_string(),
+    })
+    .to_string()
+}
+
+/// The name of a check and what is wrong, if anything
+pub type Check = (&'static str, Result<(), String>);
+
+/// Structural checks of an EPUB: the mimetype entry, the container pointing at the package
+/// document, and its manifest and spine agreeing with the files in the archive
+pub fn check_epub(path: &str) -> Result<Vec<Check>, Box<dyn std::error::Error>> {
+    fn attr(tag: &str, name: &str) -> Option<String> {
+        let re = Regex::new(&format!(r#"\s{name}\s*=\s*"([^"]*)""#)).ok()?;
+        Some(re.captures(tag)?[1].replace("&quot;", "\"").replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&"))
+    }
+    fn outcome(problems: Vec<String>) -> Result<(), String> {
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(problems.join(", ")),
+        }
+    }
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(path)?)?;
+    let mut names = vec![];
+    for i in 0..archive.len() {
+        names.push(archive.by_index_raw(i)?.name().to_string());
+    }
+    let stored = !archive.is_empty() && archive.by_index_raw(0)?.compression() == zip::CompressionMethod::Stored;
+    // Inflated here, the `zip` crate is built without a compression backend
+    let mut read = |name: &str| -> Result<String, String> {
+        let i = names.iter().position(|entry| entry == name).ok_or(format!("no '{name}'"))?;
+        let mut file = archive.by_index_raw(i).map_err(|e| format!("'{name}': {e}"))?;
+        let mut data = vec![];
+        file.read_to_end(&mut data).map_err(|e| format!("'{name}': {e}"))?;
+        let data = match file.compression() {
+            zip::CompressionMethod::Stored => data,
+            method if method == zip::CompressionMethod::DEFLATE => miniz_oxide::inflate::decompress_to_vec(&data)
+                .map_err(|e| format!("'{name}': {e:?}"))?,
+            method => return Err(format!("'{name}': unsupported compression {method}")),
+        };
+        String::from_utf8(data).map_err(|e| format!("'{name}': {e}"))
+    };
+    let mut checks = vec![];
+
+    let mimetype = match names.first().map(String::as_str) {
+        Some("mimetype") if !stored => Err("compressed".to_string()),
+        Some("mimetype") => read("mimetype").and_then(|mimetype| match mimetype.as_str() {
+            "application/epub+zip" => Ok(()),
+            mimetype => Err(format!("is '{mimetype}'")),
+        }),
+        _ => Err("not the first entry".to_string()),
+    };
+    checks.push(("mimetype", mimetype));
+
+    let container = read("META-INF/container.xml");
+    let opf_path = container.and_then(|container| {
+        let rootfile = Regex::new(r#"<rootfile\s[^>]*>"#).unwrap();
+        let tag = rootfile.find(&container).ok_or("no rootfile".to_string())?;
+        attr(tag.as_str(), "full-path").ok_or("no rootfile path".to_string())
+    });
+    let opf = opf_path.clone().and_then(|opf_path| read(&opf_path));
+    checks.push(("container", opf.as_ref().map(|_| ()).map_err(String::clone)));
+    let (Ok(opf_path), Ok(opf)) = (opf_path, opf) else {
+        return Ok(checks);
+    };
+
+    // Hrefs are relative to the package document and may be percent-encoded
+    let base = opf_path.rsplit_once('/').map_or(String::new(), |(dir, _)| format!("{dir}/"));
+    let item_re = Regex::new(r#"<item\s[^>]*>"#)?;
+    let mut ids = HashSet::new();
+    let mut listed = HashSet::from([opf_path.clone()]);
+    let (mut duplicates, mut missing) = (vec![], vec![]);
+    for item in item_re.find_iter(&opf) {
+        let (Some(id), Some(href)) = (attr(item.as_str(), "id"), attr(item.as_str(), "href")) else {
+            missing.push(format!("item without an id or href '{}'", item.as_str()));
+            continue;
+        };
+        if !ids.insert(id.clone()) {
+            duplicates.push(format!("'{id}'"));
+        }
+        let href = percent_encoding::percent_decode_str(&href).decode_utf8_lossy().to_string();
+        let name = format!("{base}{href}");
+        if !names.contains(&name) {
+            missing.push(format!("'{name}'"));
+        }
+        listed.insert(name);
+    }
+    checks.push(("manifest ids unique", outcome(duplicates)));
+    checks.push(("manifest files present", outcome(missing)));
+    let unlisted = names
+        .iter()
+        .filter(|name| *name != "mimetype" && !name.starts_with("META-INF/") && !name.ends_with('/'))
+        .filter(|name| !listed.contains(*name))
+        .map(|name| format!("'{name}'"))
+        .collect();
+    checks.push(("files in the manifest", outcome(unlisted)));
+
+    let itemref_re = Regex::new(r#"<itemref\s[^>]*>"#)?;
+    let itemrefs: Vec<_> = itemref_re.find_iter(&opf).filter_map(|itemref| attr(itemref.as_str(), "idref")).collect();
+    let mut spine: Vec<_> = itemrefs.iter().filter(|idref| !ids.contains(*idref)).map(|idref| format!("'{idref}'")).collect();
+    if itemrefs.is_empty() {
+        spine.push("empty".to_string());
+    }
+    checks.push(("spine items in the manifest", outcome(spine)));
+    Ok(checks)
+}
+
+pub struct EpubOptions {
+    // Output file name without the extension, see `FILENAME_PLACEHOLDERS`
+    pub name: String,
+    // Only the chapter files numbered from the first up to the last one are included
+    pub first_chapter: Option<u64>,
+    pub last_chapter: Option<u64>,
+    // Left out of the chapters otherwise included, e.g. a spoilery prologue or an unfinished last chapter
+    pub skip_first: usize,
+    pub skip_last: usize,
+    // Deflate level 0..9 of the archive entries, 0 stores them as is
+    pub compress_level: u8,
+    // Chapters whose title matches are labeled as extras in the TOC
+    pub extras: Option<Regex>,
+    pub epub3: bool,
+    // Series name and the position of the novel in it
+    pub series: Option<(String, f64)>,
+    // Path of the EPUB instead of the one in the work directory, "-" for stdout
+    pub output: Option<String>,
+    // Override what the cover page credits, if anything
+    pub translator: Option<String>,
+    pub editor: Option<String>,
+    // Language code of the book, otherwise detected from the chapters if `detect_lang`
+    pub lang: Option<String>,
+    pub detect_lang: bool,
+    // Left without a cover image even if one is saved
+    pub no_cover: bool,
+    // Calibre sorts the book by the alternative title, e.g. the romanized one of a CJK title
+    pub sort_by_alt_title: bool,
+    // Stylesheet file used instead of `DEFAULT_CSS`
+    pub css: Option<String>,
+}
+
+impl Default for EpubOptions {
+    fn default() -> Self {
+        Self {
+            name: "{title}".to_string(),
+            first_chapter: None,
+            last_chapter: None,
+            skip_first: 0,
+            skip_last: 0,
+            compress_level: 6,
+            extras: None,
+            epub3: false,
+            series: None,
+            output: None,
+            translator: None,
+            editor: None,
+            lang: None,
+            detect_lang: false,
+            no_cover: false,
+            sort_by_alt_title: false,
+            css: None,
+        }
+    }
+}
+
+impl Novel {
+    fn compose_title_page(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let mut buf = Buffer::new();
+        buf.void_child(Cow::Borrowed("?xml version='1.0' encoding='utf-8'?"));
+        buf.doctype();
+        let mut html = buf
+            .html()
+            .attr(r#"xmlns="http://www.w3.org/1999/xhtml""#)
+            .attr(r#"xml:lang="en-US""#);
+        let mut head = html.head().raw();
+        head.write_str(
+            r#"<meta http-equiv="Content-Type" content="text/html; charset=utf-8" />"#,
+        )?;
+        write!(
+            head,
+            r#"<link rel="stylesheet" type="text/css" href="{STYLESHEET}" />"#
+        )?;
+
+        let mut body = html.body().raw();
+        writeln!(body, "<h1>{}</h1>", xml_escape(&self.cover.title()?))?;
+        writeln!(body, r#"<p class="author">{}</p>"#, xml_escape(&self.cover.author()?))?;
+        match (self.cover.status().as_deref(), self.cover.chapter_count()) {
+            (Some("Ongoing"), Some(count)) => {
+                let today = Utc::now().format("%Y-%m-%d");
+                writeln!(body, r#"<p class="status">Ongoing, {count} chapters as of {today}</p>"#)?;
+            }
+            (Some(status), _) => writeln!(body, r#"<p class="status">{status}</p>"#)?,
+            (None, _) => {}
+        }
+        if let Some(description) = self.cover.description() {
+            writeln!(body, r#"<div class="description">"#)?;
+            for paragraph in description.lines() {
+                writeln!(body, "<p>{}</p>", xml_escape(paragraph))?;
+            }
+            writeln!(body, "</div>")?;
+        }
+        if !self.source_url.is_empty() {
+            let url = xml_escape(&self.source_url);
+            writeln!(body, r#"<p class="source"><small>Source: <a href="{url}">{url}</a></small></p>"#)?;
+        }
+        Ok(buf.finish())
+    }
+
+    // Where the book goes without `output`: the work directory, named after `name` made safe for a file name
+    fn default_output(&self, options: &EpubOptions, ext: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let series = options.series.as_ref().map(|(series, _)| series.clone()).unwrap_or_default();
+        let name = render_filename(
+            &options.name,
+            &[("title", self.cover().title()?), ("author", self.cover().author()?), ("series", series)],
+        )?;
+        Ok(format!("{}/{}.{ext}", self.workdir, self.file_name(&sanitize_filename(&name))))
+    }
+
+    // The saved chapter files with this extension that go into the book, in order: those in
+    // the range of `first_chapter` and `last_chapter`, less `skip_first` and `skip_last`
+    fn saved_chapters(&self, options: &EpubOptions, ext: &str) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+        let novel_dir = glob::Pattern::escape(&self.novel_dir()?);
+        let chapter_num_re = Regex::new(r#"^(\d+) "#)?;
+        let mut chapters = vec![];
+        for entry in glob::glob(format!("{novel_dir}/*.{ext}").as_str())? {
+            let path = entry?;
+            let file_name = path.as_path().file_name().unwrap().to_str().unwrap();
+            if options.first_chapter.is_some() || options.last_chapter.is_some() {
+                let id = chapter_num_re
+                    .captures(file_name)
+                    .and_then(|c| c[1].parse::<u64>().ok());
+                let first = options.first_chapter.unwrap_or(0);
+                let last = options.last_chapter.unwrap_or(u64::MAX);
+                if id.is_none_or(|id| id < first || id > last) {
+                    continue;
+                }
+            }
+            chapters.push(path);
+        }
+        // Trimmed after the range is applied, the ends of the range are what is skipped
+        let end = chapters.len().saturating_sub(options.skip_last);
+        chapters.truncate(end);
+        Ok(chapters.into_iter().skip(options.skip_first).collect())
+    }
+
+    /// Joins the saved text chapters of `Format::Txt` into one UTF-8 text file under the
+    /// title and the author, picked and named like the chapters and the file of `build_epub`
+    pub fn build_txt(&self, options: &EpubOptions) -> Result<String, Box<dyn std::error::Error>> {
+        let header = format!("{}\n{}\n", self.cover.title()?, self.cover.author()?);
+        self.join_chapters(options, "txt", header)
+    }
+
+    /// Joins the saved Markdown chapters of `Format::Md` into one file the same way, the
+    /// title as its top heading over the chapters' own
+    pub fn build_markdown(&self, options: &EpubOptions) -> Result<String, Box<dyn std::error::Error>> {
+        let header = format!("# {}\n\n*{}*\n", self.cover.title()?, self.cover.author()?);
+        self.join_chapters(options, "md", header)
+    }
+
+    fn join_chapters(&self, options: &EpubOptions, ext: &str, header: String) -> Result<String, Box<dyn std::error::Error>> {
+        let mut text = header;
+        for path in self.saved_chapters(options, ext)? {
+            Event::Read { path: &path.display().to_string() }.emit();
+            text.push_str("\n\n");
+            text.push_str(std::fs::read_to_string(&path)?.trim_end());
+            text.push('\n');
+        }
+        let filepath = match &options.output {
+            Some(output) => output.clone(),
+            None => self.default_output(options, ext)?,
+        };
+        match filepath.as_str() {
+            "-" => std::io::stdout().lock().write_all(text.as_bytes())?,
+            path => std::fs::write(path, text)?,
+        }
+        Ok(filepath)
+    }
+
+    // The href of a saved file in the EPUB, its name as it would be without
+    // --percent-encode-filenames, percent-encoded. `repack_epub` stores the file decoded again,
+    // so readers resolving the href find it
+    fn epub_href(&self, file_name: &str) -> String {
+        let name = match self.config.percent_encode_filenames {
+            true => percent_encoding::percent_decode_str(file_name).decode_utf8_lossy().to_string(),
+            false => file_name.to_string(),
+        };
+        percent_encoding::utf8_percent_encode(&name, HREF).to_string()
+    }
+
+    pub fn build_epub(&self, options: &EpubOptions) -> Result<String, Box<dyn std::error::Error>> {
+        let mut builder = EpubBuilder::new(ZipLibrary::new()?)?;
+        if options.epub3 {
+            builder.epub_version(EpubVersion::V30);
+        }
+        builder.metadata("author", xml_escape(&self.cover.author()?))?;
+        builder.metadata("title", xml_escape(&self.cover.title()?))?;
+        // Written into the OPF as it is
+        if let Some(description) = self.cover.description() {
+            builder.metadata("description", xml_escape(&description.replace('\n', " ")))?;
+        }
+        // Added once as `STYLESHEET` and linked from every chapter instead of inlining it
+        match &options.css {
+            Some(path) => builder.stylesheet(std::fs::File::open(path)?)?,
+            None => builder.stylesheet(DEFAULT_CSS.as_bytes())?,
+        };
+
+        builder.add_content(
+            EpubContent::new(TITLE_PAGE, self.compose_title_page()?.as_bytes())
+                .title(self.cover.title()?)
+                .reftype(ReferenceType::TitlePage),
+        )?;
+
+        let title = glob::Pattern::escape(&self.file_name(&self.cover.title()?));
+        let novel_dir = glob::Pattern::escape(&self.novel_dir()?);
+        // Without a saved cover (see --allow-no-cover) the book is built with the generated one
+        // if there is one (see --generate-cover), otherwise without any
+        let img_type = self.saved_cover_type()?.unwrap_or("svg".to_string());
+        let media_type = match img_type.as_str() {
+            "svg" => "image/svg+xml".to_string(),
+            img_type => format!("image/{img_type}"),
+        };
+        let covers = match options.no_cover {
+            true => vec![],
+            false => glob::glob(format!("{novel_dir}/{title}.{img_type}").as_str())?.collect(),
+        };
+        for entry in covers {
+            let path = entry?;
+            path.as_path().file_name().unwrap().to_str().unwrap();
+            Event::Read { path: &path.display().to_string() }.emit();
+            let content = std::fs::read(&path)?;
+            builder.add_cover_image(
+                self.epub_href(path.as_path().file_name().unwrap().to_str().unwrap()),
+                Cursor::new(content),
+                media_type.as_str(),
+            )?;
+        }
+
+        for entry in glob::glob(format!("{novel_dir}/{IMAGES_DIR}/*").as_str())? {
+            let path = entry?;
+            let file_name = path.as_path().file_name().unwrap().to_str().unwrap();
+            let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+            Event::Read { path: &path.display().to_string() }.emit();
+            builder.add_resource(
+                format!("{IMAGES_DIR}/{file_name}"),
+                std::fs::File::open(&path)?,
+                format!("image/{ext}"),
+            )?;
+        }
+
+        let chapter_id_re = Regex::new(r#"\d*? "#)?;
+        let chapters = self.saved_chapters(options, "xhtml")?;
+        let lang = match &options.lang {
+            Some(lang) => Some(lang.clone()),
+            None if options.detect_lang => {
+                // A few chapters are plenty of text to tell the language
+                let mut sample = String::new();
+                for path in chapters.iter().take(3) {
+                    let page = Html::parse_document(&std::fs::read_to_string(path)?);
+                    sample.extend(page.root_element().text());
+                }
+                let lang = detect_language(&sample);
+                match lang {
+                    Some(lang) => Event::Notice { message: &format!("Detected the language '{lang}'") }.emit(),
+                    None => Event::Notice { message: "No language detected, leaving the default" }.emit(),
+                }
+                lang.map(str::to_string)
+            }
+            None => None,
+        };
+        if let Some(lang) = lang {
+            builder.metadata("lang", lang)?;
+        }
+        // Chapters whose titles name a volume are nested under it in the TOC, the first one of
+        // the volume stands for it. Without any volumes the TOC stays flat
+        let mut volume = None;
+        for path in &chapters {
+            let file_name = path.as_path().file_name().unwrap().to_str().unwrap();
+            Event::Read { path: &path.display().to_string() }.emit();
+            // The TOC label, the file name without the number and the extension
+            let chapter_name = chapter_id_re.replace(file_name.trim_end_matches(".xhtml"), "").to_string();
+            let chapter_name = if self.config.percent_encode_filenames {
+                percent_encoding::percent_decode_str(&chapter_name)
+                    .decode_utf8_lossy()
+                    .to_string()
+            } else {
+                chapter_name
+            };
+            let chapter_volume = title_volume(&chapter_name);
+            let chapter_name = match &options.extras {
+                Some(re) if re.is_match(&chapter_name) => format!("Extra: {chapter_name}"),
+                _ => chapter_name,
+            };
+            let href = self.epub_href(file_name);
+            let content = EpubContent::new(&href, std::fs::File::open(path)?).reftype(ReferenceType::Text);
+            let content = match chapter_volume {
+                Some(label) if volume.as_ref() != Some(&label) => {
+                    let content = content.title(label.clone()).child(TocElement::new(&href, chapter_name));
+                    volume = Some(label);
+                    content
+                }
+                Some(_) => content.title(chapter_name).level(2),
+                None => {
+                    volume = None;
+                    content.title(chapter_name)
+                }
+            };
+            builder.add_content(content)?;
+        }
+        // EPUB3 readers show the nav document as the TOC, a TOC page would only repeat it
+        if !options.epub3 {
+            builder.inline_toc();
+        }
+        let default_path = self.default_output(options, "epub")?;
+        let filepath = match &options.output {
+            Some(output) => output.clone(),
+            None => default_path.clone(),
+        };
+        // `ZipLibrary` holds the whole generated archive in memory until here, its `Zip` trait is
+        // private so no file backed one can take its place. Written to a scratch file next to the
+        // default output in the work directory and repacked from there
+        let partial = ScratchFile(format!("{default_path}.part"));
+        builder.generate(std::fs::File::create(&partial.0)?)?;
+
+        let date = self.cover.updated_date().unwrap_or_else(|e| {
+            Event::Warning { message: &format!("Updated date unavailable ({e}), using the current date") }.emit();
+            Utc::now().format("%Y-%m-%d").to_string()
+        });
+        let date_re = Regex::new(r#"<dc:date>[^<]*</dc:date>"#)?;
+        let mut metadata = vec![];
+        if !self.source_url.is_empty() {
+            metadata.push(format!("<dc:source>{}</dc:source>", xml_escape(&self.source_url)));
+        }
+        if let Some(status) = self.cover.status() {
+            metadata.push(format!(r#"<meta name="novel2epub:status" content="{status}" />"#));
+            if let (Some(count), "Ongoing") = (self.cover.chapter_count(), status.as_str()) {
+                metadata.push(format!(r#"<meta name="novel2epub:chapter_count" content="{count}" />"#));
+            }
+        }
+        // Credited apart from the author with their MARC relator roles
+        let contributors = [
+            (options.translator.clone().or_else(|| self.cover.translator()), "trl"),
+            (options.editor.clone(), "edt"),
+        ];
+        for (i, (name, role)) in contributors.iter().enumerate() {
+            let Some(name) = name else {
+                continue;
+            };
+            let name = xml_escape(name);
+            if options.epub3 {
+                metadata.push(format!(r#"<dc:contributor id="contributor-{i}">{name}</dc:contributor>"#));
+                metadata.push(format!(
+                    r##"<meta refines="#contributor-{i}" property="role" scheme="marc:relators">{role}</meta>"##
+                ));
+            } else {
+                metadata.push(format!(r#"<dc:contributor opf:role="{role}">{name}</dc:contributor>"#));
+            }
+        }
+        if let Some(alt_title) = self.cover.alt_title() {
+            let alt_title = xml_escape(&alt_title);
+            // Readers take the first title as the main one
+            metadata.push(format!("<dc:title>{alt_title}</dc:title>"));
+            if options.sort_by_alt_title {
+                metadata.push(format!(r#"<meta name="calibre:title_sort" content="{alt_title}" />"#));
+            }
+        }
+        if let Some((series, index)) = &options.series {
+            let series = xml_escape(series);
+            // Calibre reads its own tags, EPUB3 readers like Apple Books only the collection ones
+            metadata.push(format!(r#"<meta name="calibre:series" content="{series}" />"#));
+            metadata.push(format!(r#"<meta name="calibre:series_index" content="{index}" />"#));
+            if options.epub3 {
+                metadata.push(format!(r#"<meta property="belongs-to-collection" id="series">{series}</meta>"#));
+                metadata.push(r##"<meta refines="#series" property="collection-type">series</meta>"##.to_string());
+                metadata.push(format!(r##"<meta refines="#series" property="group-position">{index}</meta>"##));
+            }
+        }
+        let out: Box<dyn OWrite> = match filepath.as_str() {
+            "-" => Box::new(std::io::stdout().lock()),
+            path => Box::new(std::fs::File::create(path)?),
+        };
+        let out = std::io::BufWriter::new(out);
+        let out = EpubZip::new(out, options.compress_level);
+        repack_epub(std::fs::File::open(&partial.0)?, out, |opf| {
+            let opf = date_re.replace(opf, format!("<dc:date>{date}</dc:date>"));
+            let metadata: String = metadata.iter().map(|line| format!("    {line}\n")).collect();
+            Ok(opf.replace("  </metadata>", &format!("{metadata}  </metadata>")))
+        })?
+        .flush()?;
+        Ok(filepath)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{novel::Config, pages::check_xml};
+
+    // A scratch file in the temp directory, removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("novel2epub-{}-{name}", std::process::id())))
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    // A scratch directory in the temp directory, removed with what's in it when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("novel2epub-{}-{name}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // Builds the EPUB of `pages` (file name, html) imported like --from-dir does, then checks it
+    // and that its documents are well-formed, stored to read them back
+    fn check_imported_book(name: &str, title: &str, config: Config, options: EpubOptions, pages: &[(&str, &str)]) {
+        let dir = TempDir::new(name);
+        let pages_dir = format!("{}/pages", dir.path());
+        std::fs::create_dir_all(&pages_dir).unwrap();
+        for (file_name, html) in pages {
+            std::fs::write(format!("{pages_dir}/{file_name}"), html).unwrap();
+        }
+        let novel = Novel::from_metadata(title, "Tom & Jerry", None, dir.path(), config).unwrap();
+        novel.import(&pages_dir, None).unwrap();
+        if novel.config().generate_cover {
+            novel.cover_generate().unwrap();
+        }
+        let output = format!("{}/book.epub", dir.path());
+        let options = EpubOptions { output: Some(output.clone()), compress_level: 0, ..options };
+        novel.build_epub(&options).unwrap();
+        for (name, result) in check_epub(&output).unwrap() {
+            assert_eq!(result, Ok(()), "{name}");
+        }
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(&output).unwrap()).unwrap();
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i).unwrap();
+            if [".xhtml", ".opf", ".ncx", ".svg"].iter().any(|extension| file.name().ends_with(extension)) {
+                let mut text = String::new();
+                file.read_to_string(&mut text).unwrap();
+                assert_eq!(check_xml(&text), Ok(()), "{}", file.name());
+            }
+        }
+    }
+
+    #[test]
+    fn percent_encoded_file_names_pass_the_checks() {
+        let config = Config { percent_encode_filenames: true, ..Config::default() };
+        let page = "<html><head><title>第一章 開始</title></head><body><p>text</p></body></html>";
+        check_imported_book("percent", "Book", config, EpubOptions::default(), &[("1.html", page)]);
+    }
+
+    #[test]
+    fn checksums_of_the_saved_files_are_written_once_dropped() {
+        let dir = TempDir::new("checksums");
+        let pages_dir = format!("{}/pages", dir.path());
+        std::fs::create_dir_all(&pages_dir).unwrap();
+        let page = "<html><head><title>One</title></head><body><p>text</p></body></html>";
+        std::fs::write(format!("{pages_dir}/1.html"), page).unwrap();
+        let novel = Novel::from_metadata("Book", "Author", None, dir.path(), Config::default()).unwrap();
+        novel.import(&pages_dir, None).unwrap();
+        let novel_dir = novel.novel_dir().unwrap();
+        let sums = format!("{novel_dir}/SHA256SUMS");
+        assert!(!std::path::Path::new(&sums).exists());
+        drop(novel);
+        let chapter = std::fs::read(format!("{novel_dir}/00000001 One.xhtml")).unwrap();
+        let sums = std::fs::read_to_string(sums).unwrap();
+        assert_eq!(sums, format!("{}  00000001 One.xhtml\n", crate::novel::sha256_hex(&chapter)));
+    }
+
+    #[test]
+    fn failed_build_leaves_no_scratch_file() {
+        let dir = TempDir::new("scratch");
+        let novel = Novel::from_metadata("Book", "Author", None, dir.path(), Config::default()).unwrap();
+        let output = format!("{}/missing/book.epub", dir.path());
+        let options = EpubOptions { output: Some(output), ..EpubOptions::default() };
+        assert!(novel.build_epub(&options).is_err());
+        let part = format!("{}.part", novel.default_output(&options, "epub").unwrap());
+        assert!(!std::path::Path::new(&part).exists());
+    }
+
+    #[test]
+    fn default_output_keeps_the_rendered_name_in_the_work_directory() {
+        let dir = TempDir::new("output-name");
+        let novel = Novel::from_metadata("Book", "AC/DC", None, dir.path(), Config::default()).unwrap();
+        let options = EpubOptions { name: "{author} - {title}".to_string(), ..EpubOptions::default() };
+        assert_eq!(novel.default_output(&options, "epub").unwrap(), format!("{}/ACDC - Book.epub", dir.path()));
+    }
+
+    #[test]
+    fn titles_with_ampersands_pass_the_checks() {
+        let page = "<html><head><title>Tom &amp; Jerry</title></head><body><p>text</p></body></html>";
+        for epub3 in [false, true] {
+            let options = EpubOptions { epub3, ..EpubOptions::default() };
+            let config = Config { generate_cover: true, ..Config::default() };
+            check_imported_book("ampersand", "Cats & Dogs", config, options, &[("1.html", page)]);
+        }
+    }
+
+    fn generated_book(chapter_file: &str) -> Vec<u8> {
+        let mut builder = EpubBuilder::new(ZipLibrary::new().unwrap()).unwrap();
+        builder.metadata("title", "Test").unwrap();
+        let chapter = "<html xmlns=\"http://www.w3.org/1999/xhtml\"><head><title>t</title></head><body><p>text</p></body></html>";
+        builder
+            .add_content(EpubContent::new(chapter_file, chapter.as_bytes()).title("t").reftype(ReferenceType::Text))
+            .unwrap();
+        let mut book = vec![];
+        builder.generate(&mut book).unwrap();
+        book
+    }
+
+    #[test]
+    fn repacked_cjk_chapter_names_stay_utf8() {
+        let file = TempFile::new("cjk.epub");
+        let out = std::fs::File::create(file.path()).unwrap();
+        let book = Cursor::new(generated_book("00000001 第一章 開始.xhtml"));
+        repack_epub(book, EpubZip::new(out, 6), |opf| Ok(opf.to_string())).unwrap();
+
+        let archive = zip::ZipArchive::new(std::fs::File::open(file.path()).unwrap()).unwrap();
+        assert!(archive.file_names().any(|name| name == "OEBPS/00000001 第一章 開始.xhtml"));
+        for (name, result) in check_epub(file.path()).unwrap() {
+            assert_eq!(result, Ok(()), "{name}");
+        }
+    }
+
+    #[test]
+    fn repacked_to_a_file_matches_repacked_in_memory() {
+        let book = generated_book("00000001 Chapter 1.xhtml");
+        let modified = chrono::NaiveDate::from_ymd_opt(2024, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        let patch = |opf: &str| Ok(opf.replace("  </metadata>", "    <dc:source>s</dc:source>\n  </metadata>"));
+        let in_memory = EpubZip { modified, ..EpubZip::new(vec![], 6) };
+        let in_memory = repack_epub(Cursor::new(book.clone()), in_memory, patch).unwrap();
+
+        let file = TempFile::new("repacked.epub");
+        let out = std::io::BufWriter::new(std::fs::File::create(file.path()).unwrap());
+        let out = EpubZip { modified, ..EpubZip::new(out, 6) };
+        repack_epub(Cursor::new(book), out, patch).unwrap().flush().unwrap();
+        assert_eq!(std::fs::read(file.path()).unwrap(), in_memory);
+    }
+}
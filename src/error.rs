@@ -0,0 +1,73 @@
+//! The errors of fetching and saving a novel
+
+use std::fmt::Display;
+
+#[derive(Debug)]
+pub enum NovelError {
+    Http(String),
+    // The request didn't get an answer at all, e.g. DNS or connection failures
+    Network(String),
+    // The connection or the answer took longer than the timeout
+    Timeout(String),
+    Empty,
+    Attr(String),
+    // What didn't match, and the page it was looked for on once that is known
+    Selector { path: String, page: Option<String> },
+    SelectorParse(String),
+    InvalidUrl,
+    Image,
+    Locked(String),
+    Challenge(String),
+    Loop(String),
+    // A "coming soon" or empty page behind the last chapter's next link
+    Placeholder(String),
+    Other(String)
+}
+
+impl std::error::Error for NovelError {}
+
+impl Display for NovelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NovelError::Http(status) => write!(f, "HTTP error: {status}"),
+            NovelError::Network(error) => write!(f, "Network error: {error}"),
+            NovelError::Timeout(url) => write!(f, "'{url}' timed out, a longer --timeout may help on a slow connection"),
+            NovelError::Empty => write!(f, "No chapter loaded"),
+            NovelError::Attr(name) => write!(f, "Attribute not found: '{name}'"),
+            NovelError::Selector { path, page: None } => write!(f, "Selector not found: '{path}'"),
+            NovelError::Selector { path, page: Some(page) } => write!(f, "Selector '{path}' not found on {page}"),
+            NovelError::SelectorParse(path) => write!(f, "Invalid selector: '{path}'"),
+            NovelError::InvalidUrl => write!(f, "Invalid url"),
+            NovelError::Image => write!(f, "Unable to determine the cover image type"),
+            NovelError::Locked(path) => write!(
+                f,
+                "'{path}' is held by another run, use --force-unlock if it is left over from a crash"
+            ),
+            NovelError::Challenge(url) => write!(
+                f,
+                "'{url}' answered with a browser challenge instead of the page, \
+                 retry later or supply the cookies of a browser session that passed it"
+            ),
+            NovelError::Loop(url) => write!(f, "'{url}' was already visited"),
+            NovelError::Placeholder(url) => write!(f, "'{url}' is a placeholder, not a chapter"),
+            NovelError::Other(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl NovelError {
+    /// Whether the error is how the end of the novel shows up, rather than a failure:
+    /// the next link of the last chapter going to a placeholder. A last chapter without
+    /// a next link is told apart before following it, see `Novel::next`
+    pub fn is_end(&self) -> bool {
+        matches!(self, NovelError::Placeholder(_))
+    }
+
+    pub(crate) fn from_reqwest(url: &str, error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            NovelError::Timeout(url.to_string())
+        } else {
+            NovelError::Network(error.to_string())
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! What a run reports, as log lines or JSON objects
+
+use log::{log, Level};
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// Set by `--json`, switches the output to one JSON object per line
+pub static JSON_EVENTS: AtomicBool = AtomicBool::new(false);
+
+/// Everything the tool reports while running, printed either as a human readable
+/// log line or as a JSON object for automation.
+pub enum Event<'a> {
+    Request { url: &'a str, status: u16 },
+    Read { path: &'a str },
+    CoverSaved { path: &'a str },
+    ChapterSaved { id: u64, title: &'a str, path: &'a str },
+    ChapterSkipped { id: u64, title: &'a str, reason: &'a str },
+    Notice { message: &'a str },
+    // Something went wrong but the run goes on, e.g. an image left out
+    Warning { message: &'a str },
+    // Details shown with -v, e.g. which selector matched
+    Debug { message: &'a str },
+    Done { chapters: u64, skipped: u64, epub: Option<&'a str> },
+    NovelFailed { url: &'a str, error: &'a str },
+    BatchDone { succeeded: usize, failed: usize },
+    Check { name: &'a str, error: Option<&'a str> },
+    CheckDone { passed: usize, failed: usize },
+    ChapterGap { previous: u64, found: u64, previous_url: &'a str, url: &'a str },
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut json = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+impl Event<'_> {
+    // Printed through the `log` crate, nothing shows unless the embedding program sets up a logger
+    pub fn emit(&self) {
+        let line = if JSON_EVENTS.load(Ordering::Relaxed) {
+            self.json()
+        } else {
+            self.to_string()
+        };
+        log!(self.level(), "{line}");
+    }
+
+    fn level(&self) -> Level {
+        match self {
+            Event::Warning { .. } | Event::ChapterGap { .. } | Event::Check { error: Some(_), .. } => Level::Warn,
+            Event::NovelFailed { .. } => Level::Error,
+            Event::Debug { .. } => Level::Debug,
+            _ => Level::Info,
+        }
+    }
+
+    fn json(&self) -> String {
+        match self {
+            Event::Request { url, status } => format!(
+                r#"{{"event":"request","url":{},"status":{status}}}"#,
+                json_string(url)
+            ),
+            Event::Read { path } => format!(r#"{{"event":"read","path":{}}}"#, json_string(path)),
+            Event::CoverSaved { path } => format!(
+                r#"{{"event":"cover_saved","path":{}}}"#,
+                json_string(path)
+            ),
+            Event::ChapterSaved { id, title, path } => format!(
+                r#"{{"event":"chapter_saved","id":{id},"title":{},"path":{}}}"#,
+                json_string(title),
+                json_string(path)
+            ),
+            Event::ChapterSkipped { id, title, reason } => format!(
+                r#"{{"event":"chapter_skipped","id":{id},"title":{},"reason":{}}}"#,
+                json_string(title),
+                json_string(reason)
+            ),
+            Event::Notice { message } => format!(
+                r#"{{"event":"notice","message":{}}}"#,
+                json_string(message)
+            ),
+            Event::Warning { message } => format!(
+                r#"{{"event":"warning","message":{}}}"#,
+                json_string(message)
+            ),
+            Event::Debug { message } => format!(
+                r#"{{"event":"debug","message":{}}}"#,
+                json_string(message)
+            ),
+            Event::Done { chapters, skipped, epub } => format!(
+                r#"{{"event":"done","chapters":{chapters},"skipped":{skipped},"epub":{}}}"#,
+                epub.map_or("null".to_string(), json_string)
+            ),
+            Event::NovelFailed { url, error } => format!(
+                r#"{{"event":"novel_failed","url":{},"error":{}}}"#,
+                json_string(url),
+                json_string(error)
+            ),
+            Event::BatchDone { succeeded, failed } => format!(
+                r#"{{"event":"batch_done","succeeded":{succeeded},"failed":{failed}}}"#
+            ),
+            Event::Check { name, error } => format!(
+                r#"{{"event":"check","name":{},"error":{}}}"#,
+                json_string(name),
+                error.map_or("null".to_string(), json_string)
+            ),
+            Event::CheckDone { passed, failed } => format!(
+                r#"{{"event":"check_done","passed":{passed},"failed":{failed}}}"#
+            ),
+            Event::ChapterGap { previous, found, previous_url, url } => format!(
+                r#"{{"event":"chapter_gap","previous":{previous},"found":{found},"previous_url":{},"url":{}}}"#,
+                json_string(previous_url),
+                json_string(url)
+            ),
+        }
+    }
+}
+
+impl Display for Event<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::Request { url, status } => write!(f, "Request url({status}): '{url}'"),
+            Event::Read { path } => write!(f, "Reading '{path}'"),
+            Event::CoverSaved { path } | Event::ChapterSaved { path, .. } => {
+                write!(f, "Save to '{path}'")
+            }
+            Event::ChapterSkipped { title, reason, .. } => write!(f, "Skip chapter '{title}': {reason}"),
+            Event::Notice { message } | Event::Warning { message } | Event::Debug { message } => write!(f, "{message}"),
+            Event::Done { chapters, skipped, epub } => {
+                write!(f, "Saved {chapters} chapters, skipped {skipped}")?;
+                match epub {
+                    Some(epub) => write!(f, ", epub '{epub}'"),
+                    None => Ok(()),
+                }
+            }
+            Event::NovelFailed { url, error } => write!(f, "Failed '{url}': {error}"),
+            Event::BatchDone { succeeded, failed } => {
+                write!(f, "Batch done, {succeeded} novels succeeded, {failed} failed")
+            }
+            Event::Check { name, error: None } => write!(f, "PASS {name}"),
+            Event::Check { name, error: Some(error) } => write!(f, "FAIL {name}: {error}"),
+            Event::CheckDone { passed, failed } => {
+                write!(f, "{passed} checks passed, {failed} failed")
+            }
+            Event::ChapterGap { previous, found, previous_url, url } => write!(
+                f,
+                "Chapter {found} follows chapter {previous}, missing chapters between '{previous_url}' and '{url}'"
+            ),
+        }
+    }
+}
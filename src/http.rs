@@ -0,0 +1,226 @@
+//! The HTTP client, with its retries and timeouts
+
+use crate::{
+    error::NovelError,
+    event::Event,
+    novel::{canonicalize_url, sha256_hex, Config},
+};
+use chrono::{DateTime, Utc};
+use reqwest::{
+    blocking::{Client, Response},
+    header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER},
+    StatusCode,
+};
+use scraper::Html;
+use std::{path::PathBuf, time::Duration};
+
+// Retries of a failed request and the pause before the first one, doubled for each next one
+const MAX_RETRIES: u32 = 3;
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+// A stalled connection fails after these instead of hanging the run, and is retried like a dropped one.
+// The request one covers the whole answer, generous enough for a large cover on a slow link
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Cached pages older than this are fetched again, short enough that a later run still sees new chapters
+const CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+// Some sites block the default one of reqwest, this at least says what is asking
+const USER_AGENT: &str = concat!("novel2epub/", env!("CARGO_PKG_VERSION"));
+
+/// The pause asked for by the `Retry-After` header, either in seconds or as an HTTP date
+fn retry_after(resp: &Response) -> Option<Duration> {
+    let value = resp.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+// Markers of the Cloudflare "checking your browser" interstitial. Not "challenge-platform",
+// the script under that path is loaded by normal pages of sites behind Cloudflare too
+const CHALLENGE_MARKERS: [&str; 4] = [
+    "cf-browser-verification",
+    "cf_chl_opt",
+    "Checking your browser before accessing",
+    "<title>Just a moment...</title>",
+];
+
+fn is_challenge(body: &str) -> bool {
+    CHALLENGE_MARKERS.iter().any(|marker| body.contains(marker))
+}
+
+/// The HTTP client with how persistently its requests are retried
+#[derive(Clone)]
+pub struct HttpClient {
+    client: Client,
+    max_retries: u32,
+    retry_base_delay: Duration,
+    // Where the fetched pages are kept and for how long they are used, see `request_text`
+    cache_dir: Option<PathBuf>,
+    cache_ttl: Duration,
+    refresh: bool,
+}
+
+impl HttpClient {
+    pub fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            headers.append(HeaderName::from_bytes(name.as_bytes())?, HeaderValue::from_str(value)?);
+        }
+        let timeout = config.timeout.unwrap_or(REQUEST_TIMEOUT);
+        let client = Client::builder()
+            .connect_timeout(timeout.min(CONNECT_TIMEOUT))
+            .timeout(timeout)
+            .danger_accept_invalid_certs(config.tls_insecure)
+            .user_agent(config.user_agent.as_deref().unwrap_or(USER_AGENT))
+            .default_headers(headers)
+            .build()?;
+        Ok(Self {
+            client,
+            max_retries: config.max_retries.unwrap_or(MAX_RETRIES),
+            retry_base_delay: config.retry_base_delay.unwrap_or(RETRY_BASE_DELAY),
+            cache_dir: config.cache_dir.as_ref().map(PathBuf::from),
+            cache_ttl: config.cache_ttl.unwrap_or(CACHE_TTL),
+            refresh: config.refresh,
+        })
+    }
+
+    // Dropped or stalled connections, 5xx and 429 answers are retried after a growing pause,
+    // the cover and the chapters go through here alike. The body is read by `read` within the
+    // retries, a timeout halfway through it is retried too. Once the retries are used up the
+    // last answer is returned, the callers turn its status into `NovelError::Http`
+    fn request<T>(
+        &self,
+        url: &str,
+        read: impl Fn(Response) -> reqwest::Result<T>,
+    ) -> Result<(StatusCode, T), Box<dyn std::error::Error>> {
+        let mut retries = 0;
+        loop {
+            let result = self.client.get(url).send();
+            let retry = match &result {
+                Ok(resp) => resp.status().is_server_error() || resp.status() == StatusCode::TOO_MANY_REQUESTS,
+                Err(_) => true,
+            };
+            let asked_pause = result
+                .as_ref()
+                .ok()
+                .filter(|resp| resp.status() == StatusCode::TOO_MANY_REQUESTS)
+                .and_then(retry_after);
+            let exhausted = retries >= self.max_retries;
+            if !retry || exhausted {
+                let resp = result.map_err(|e| NovelError::from_reqwest(url, e))?;
+                let status = resp.status();
+                match read(resp) {
+                    Ok(body) => return Ok((status, body)),
+                    Err(e) if e.is_timeout() && !exhausted => {}
+                    Err(e) => return Err(NovelError::from_reqwest(url, e).into()),
+                }
+            }
+            let pause = asked_pause.unwrap_or_else(|| self.retry_base_delay.saturating_mul(1 << retries.min(16)));
+            Event::Warning { message: &format!("Request '{url}' failed, retry in {:.1}s", pause.as_secs_f64()) }.emit();
+            std::thread::sleep(pause);
+            retries += 1;
+        }
+    }
+
+    pub fn request_page(&self, url: &str) -> Result<Html, Box<dyn std::error::Error>> {
+        let (status, body) = self.request_text(url)?;
+        Self::parse_page(url, status, &body)
+    }
+
+    // The raw status and body, `Html` can't be sent across threads so a prefetch stops here.
+    // Served from the cache while fresh, a fetched page is cached unless it is an error or a challenge
+    pub(crate) fn request_text(&self, url: &str) -> Result<(u16, String), Box<dyn std::error::Error>> {
+        if let Some(body) = self.fresh_cache_path(url).and_then(|path| std::fs::read_to_string(path).ok()) {
+            Event::Debug { message: &format!("'{url}' from the cache") }.emit();
+            return Ok((200, body));
+        }
+        let (status, body) = self.request_text_fresh(url)?;
+        if let (Some(path), 200) = (self.cache_path(url), status) {
+            if !is_challenge(&body) {
+                let cached = path.parent().map_or(Ok(()), std::fs::create_dir_all).and_then(|_| std::fs::write(&path, &body));
+                if let Err(e) = cached {
+                    Event::Warning { message: &format!("Caching '{url}' at '{}' failed: {e}", path.display()) }.emit();
+                }
+            }
+        }
+        Ok((status, body))
+    }
+
+    /// Fetches the page past the cache, e.g. to download a broken chapter again
+    pub(crate) fn request_text_fresh(&self, url: &str) -> Result<(u16, String), Box<dyn std::error::Error>> {
+        let (status, body) = self.request(url, Response::text)?;
+        Ok((status.as_u16(), body))
+    }
+
+    // Keyed by the SHA-256 of the canonical url, a tracking parameter or fragment gets no page of its own
+    fn cache_path(&self, url: &str) -> Option<PathBuf> {
+        Some(self.cache_dir.as_ref()?.join(format!("{}.html", sha256_hex(canonicalize_url(url).as_bytes()))))
+    }
+
+    /// Whether `request_text` serves the page at `url` from the cache
+    pub(crate) fn is_cached(&self, url: &str) -> bool {
+        self.fresh_cache_path(url).is_some()
+    }
+
+    // The cached page younger than the TTL, none with `refresh`
+    fn fresh_cache_path(&self, url: &str) -> Option<PathBuf> {
+        if self.refresh {
+            return None;
+        }
+        let path = self.cache_path(url)?;
+        let age = std::fs::metadata(&path).ok()?.modified().ok()?.elapsed().unwrap_or_default();
+        (age < self.cache_ttl).then_some(path)
+    }
+
+    pub(crate) fn parse_page(url: &str, status: u16, body: &str) -> Result<Html, Box<dyn std::error::Error>> {
+        Event::Request { url, status }.emit();
+        // Challenges come either as 200 or as 403/503, the body tells them apart from real errors
+        if is_challenge(body) {
+            Err(Box::new(NovelError::Challenge(url.to_string())))
+        } else if status != 200 {
+            Err(Box::new(NovelError::Http(status.to_string())))
+        } else {
+            Ok(Html::parse_document(body))
+        }
+    }
+
+    pub fn request_data(&self, url: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let (status, data) = self.request(url, Response::bytes)?;
+        Event::Request { url, status: status.as_u16() }.emit();
+        if status.as_u16() != 200 {
+            Err(Box::new(NovelError::Http(
+                status.as_str().to_string(),
+            )))
+        } else {
+            Ok(data.to_vec())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenge_platform_script_is_no_challenge() {
+        let chapter = r#"<html><head><title>Chapter 1</title><script src="/cdn-cgi/challenge-platform/scripts/jsd/main.js"></script></head><body><p>Text</p></body></html>"#;
+        assert!(!is_challenge(chapter));
+        let interstitial = r#"<html><head><title>Just a moment...</title></head><body><script>window._cf_chl_opt={}</script></body></html>"#;
+        assert!(is_challenge(interstitial));
+    }
+
+    #[test]
+    fn cache_keyed_by_canonical_url() {
+        let client = HttpClient {
+            cache_dir: Some(PathBuf::from("cache")),
+            ..HttpClient::new(&Config::default()).unwrap()
+        };
+        let url = "https://www.lightnovelworld.com/novel/n/chapter-1?b=2&a=1";
+        assert_eq!(client.cache_path(url), client.cache_path("https://www.lightnovelworld.com/novel/n/chapter-1?a=1&utm_source=x&b=2#top"));
+        assert_ne!(client.cache_path(url), client.cache_path("https://www.lightnovelworld.com/novel/n/chapter-2?b=2&a=1"));
+    }
+}
@@ -0,0 +1,36 @@
+//! Fetches a novel chapter by chapter and builds an EPUB of it, the `novel2epub` binary is a
+//! command line front end of this.
+//!
+//! ```no_run
+//! use novel2epub::{Config, EpubOptions, Novel};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut novel = Novel::new("https://www.lightnovelworld.com/novel/some-novel", "novel", Config::default())?;
+//! novel.cover_img_save(None)?;
+//! while novel.next().is_some() {
+//!     novel.chapter_save()?;
+//! }
+//! if let Some(e) = novel.take_error() {
+//!     return Err(e);
+//! }
+//! let epub = novel.build_epub(&EpubOptions::default())?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod epub;
+mod error;
+mod event;
+mod http;
+mod novel;
+mod pages;
+
+pub use epub::{check_epub, validate_filename_template, Check, EpubOptions, EXTRA_CHAPTERS};
+pub use error::NovelError;
+pub use event::{json_string, Event, JSON_EVENTS};
+pub use http::HttpClient;
+pub use novel::{resolve_url, sha256_hex, Chapters, Config, Format, Novel, NovelLock};
+pub use pages::{
+    parse_replacement, site_adapter, title_chapter_number, title_volume, ChapterPage, CoverPage, LightNovelWorldAdapter,
+    SiteAdapter, FOOTNOTES, SITES,
+};
@@ -1,418 +1,644 @@
-use epub_builder::{EpubBuilder, EpubContent, ReferenceType, ZipLibrary};
-use html_builder::{Buffer, Html5};
+use chrono::{DateTime, SecondsFormat, Utc};
+use env_logger::Target;
+use log::LevelFilter;
+use novel2epub::*;
 use regex::Regex;
-use scraper::{Html, Selector};
+use reqwest::header::{HeaderName, HeaderValue};
 use std::{
-    borrow::Cow,
-    fmt::{Display, Write},
-    io::{Cursor, Write as OWrite},
+    collections::HashMap,
+    io::{IsTerminal, Write},
     env,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
 };
 
-#[derive(Debug)]
-enum NovelError {
-    Http(String),
-    Empty,
-    Attr(String),
-    Selector(String),
-    InvalidUrl,
-    Image,
-    Other(String)
+// Exit code of a run stopped by --timeout-total, its EPUB only has the chapters saved by then
+const EXIT_TIME_LIMITED: i32 = 3;
+
+
+#[derive(Default)]
+struct Args {
+    urls: Vec<String>,
+    urls_from_stdin: bool,
+    // Where the novel directories and the EPUBs go, "novel" by default
+    out_dir: Option<String>,
+    check_site: bool,
+    dry_run: bool,
+    verify_links: bool,
+    skip_title: Option<Regex>,
+    extra_chapters: Option<Regex>,
+    force_unlock: bool,
+    resume: bool,
+    // Save the chapters saved by earlier runs again
+    force: bool,
+    verify_as_you_go: bool,
+    lang: Option<String>,
+    detect_lang: bool,
+    sort_by_alt_title: bool,
+    css: Option<String>,
+    preview: Option<u64>,
+    // Chapters numbered below it are walked through but not saved
+    start_chapter: Option<u64>,
+    // Only the chapters numbered from..=to are saved and built, the run stops after `to`
+    from: Option<u64>,
+    to: Option<u64>,
+    json: bool,
+    // Write the novel metadata as JSON next to the EPUB
+    metadata_json: bool,
+    // Join the Markdown chapters into one file
+    single_file: bool,
+    // -v and -q steps from the info level, RUST_LOG takes precedence
+    verbosity: i8,
+    progress: bool,
+    compress_level: Option<u8>,
+    min_content_length: Option<usize>,
+    skip_short: bool,
+    allow_no_cover: bool,
+    no_cover: bool,
+    // Take the cover from the images of this chapter instead of the cover page
+    cover_from_chapter: Option<u64>,
+    // Download the covers of a batch up front, this many at a time
+    parallel_covers: Option<usize>,
+    yes: bool,
+    timeout_total: Option<Duration>,
+    epub3: bool,
+    series: Option<String>,
+    series_index: Option<f64>,
+    build_range: Option<(u64, u64)>,
+    filename_template: Option<String>,
+    skip_first: usize,
+    skip_last: usize,
+    translator: Option<String>,
+    editor: Option<String>,
+    output: Option<String>,
+    log_file: Option<String>,
+    log_append: bool,
+    // Assemble the EPUB from the pages saved in this directory, described by the title, author and cover
+    from_dir: Option<String>,
+    title: Option<String>,
+    author: Option<String>,
+    cover: Option<String>,
+    // Also write what the run did as JSON here
+    manifest_out: Option<String>,
+    // Only check the structure of this EPUB
+    validate_only: Option<String>,
+    config: Config,
 }
 
-impl std::error::Error for NovelError {}
-
-impl Display for NovelError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self)
+impl Args {
+    // Relative to the current directory unless absolute, a trailing slash would double in the paths
+    fn workdir(&self) -> &str {
+        self.out_dir.as_deref().map_or("novel", |dir| dir.trim_end_matches('/'))
     }
 }
 
-struct CoverPage {
-    page: Html,
+fn arg_value(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(args
+        .next()
+        .ok_or(Box::new(NovelError::Other(format!("Missing value for '{flag}'"))))?)
 }
 
-impl CoverPage {
-    fn new(page: Html) -> Self {
-        Self { page }
-    }
-
-    fn title(&self) -> Result<String, Box<dyn std::error::Error>> {
-        // #novel > header > div.header-body.container > div.novel-info > div.main-head > h1
-        let selector_path = "h1.novel-title";
-        let selector = Selector::parse(selector_path).unwrap();
-        let title = self
-            .page
-            .select(&selector)
-            .next()
-            .ok_or(Box::new(NovelError::Selector(selector_path.to_string())))?
-            .inner_html()
-            .as_str()
-            .trim()
-            .to_string();
-        let title = Regex::new(r#"[\\|/|:|"|\n|\r\n|?]{1,}"#)?
-            .replace_all(&title, "")
-            .to_string();
-        let title = Regex::new(r#"\s{2,}"#)?
-            .replace_all(title.trim(), "")
-            .to_string();
-        Ok(title)
-    }
-
-    fn author(&self) -> Result<String, Box<dyn std::error::Error>> {
-        // #novel > header > div.header-body.container > div.novel-info > div.main-head > div.author > a > span
-        let selector_path = "div.author > a > span";
-        let selector = Selector::parse(selector_path).unwrap();
-        let author = self
-            .page
-            .select(&selector)
-            .next()
-            .ok_or(Box::new(NovelError::Selector(selector_path.to_string())))?
-            .inner_html()
-            .as_str()
-            .trim()
-            .to_string();
-        Ok(author)
-    }
-    fn cover_img_url(&self) -> Result<String, Box<dyn std::error::Error>> {
-        //#novel > header > div.header-body.container > div.fixed-img > figure > img
-        // #novel > header > div.header-body.container > div.fixed-img > figure > img
-        let selector_path = "div.fixed-img > figure > img";
-        let attr_name = "data-src";
-        let selector = Selector::parse(selector_path).unwrap();
-        let cover_url = self
-            .page
-            .select(&selector)
-            .next()
-            .ok_or(Box::new(NovelError::Selector(selector_path.to_string())))?
-            .value()
-            .attr(attr_name)
-            .ok_or(Box::new(NovelError::Attr(attr_name.to_string())))?
-            .to_string();
-        Ok(cover_url)
-    }
-
-    fn cover_img_type(&self) -> Result<String, Box<dyn std::error::Error>> {
-        let img_url = self.cover_img_url()?;
-        let re = Regex::new(r#"([[:alpha:]]+)(?:\?v=\d*)??$"#)?;
-        // let re = Regex::new(r#"([[:alpha:]]+)$"#)?;
-        let ext = re
-            .captures(&img_url)
-            .ok_or(Box::new(NovelError::Image))?
-            .get(1)
-            .unwrap()
-            .as_str()
-            .to_string();
-        Ok(ext)
-    }
-
-    fn chapter_first_url(&self) -> Result<String, Box<dyn std::error::Error>> {
-        let selector_path = "#readchapterbtn";
-        let attr_name = "href";
-
-        let selector = Selector::parse(selector_path).unwrap();
-        let chapter_url = self
-            .page
-            .select(&selector)
-            .next()
-            .ok_or(Box::new(NovelError::Selector(selector_path.to_string())))?
-            .value()
-            .attr(attr_name)
-            .ok_or(Box::new(NovelError::Attr(attr_name.to_string())))?
-            .to_string();
-        Ok(chapter_url)
-    }
+// One url per line, blank lines and `#` comments are ignored
+// "-" reads them from stdin
+fn read_batch(path: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let text = match path {
+        "-" => std::io::read_to_string(std::io::stdin())?,
+        path => std::fs::read_to_string(path)?,
+    };
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
 }
 
-struct ChapterPage {
-    page: Html,
+fn parse_header(header: &str) -> Result<(String, String), Box<dyn std::error::Error>> {
+    let (name, value) = header
+        .split_once(':')
+        .filter(|(name, value)| {
+            HeaderName::from_bytes(name.trim().as_bytes()).is_ok() && HeaderValue::from_str(value.trim()).is_ok()
+        })
+        .ok_or(Box::new(NovelError::Other(format!("Malformed header '{header}', expected 'Name: Value'"))))?;
+    Ok((name.trim().to_string(), value.trim().to_string()))
 }
 
-impl ChapterPage {
-    fn new(page: Html) -> Self {
-        Self { page }
-    }
-    fn title(&self) -> Result<String, Box<dyn std::error::Error>> {
-        let selector_path = "span.chapter-title";
-
-        let selector = Selector::parse(selector_path).unwrap();
-        let title = self
-            .page
-            .select(&selector)
-            .next()
-            .ok_or(Box::new(NovelError::Selector(selector_path.to_string())))?
-            .inner_html()
-            .as_str()
-            .trim()
-            .to_string();
-
-        let title = Regex::new(r#"[\\|/|:|"|\n|\r\n|?]{1,}"#)?
-            .replace_all(&title, "")
-            .to_string();
-        let title = Regex::new(r#"\s{2,}"#)?
-            .replace_all(title.trim(), "")
-            .to_string();
-        Ok(title)
-    }
-
-    fn content(&self) -> Result<String, Box<dyn std::error::Error>> {
-        let selector_path = "div.chapter-content";
-
-        let selector = Selector::parse(selector_path).unwrap();
-        let content = self
-            .page
-            .select(&selector)
-            .next()
-            .ok_or(Box::new(NovelError::Selector(selector_path.to_string())))?
-            .inner_html()
-            .as_str()
-            .trim()
-            .to_string();
-
-        Ok(Self::remove_ad(content)?)
-    }
-
-    fn chapter_next_url(&self) -> Result<String, Box<dyn std::error::Error>> {
-        // #chapter-article > section > div.chapternav.skiptranslate > a.button.nextchap
-        let selector_path = "a.button.nextchap";
-        let attr_name = "href";
-
-        let selector = Selector::parse(selector_path).unwrap();
-        let chapter_url = self
-            .page
-            .select(&selector)
-            .next()
-            .ok_or(Box::new(NovelError::Selector(selector_path.to_string())))?
-            .value()
-            .attr(attr_name)
-            .ok_or(Box::new(NovelError::Attr(attr_name.to_string())))?
-            .to_string();
-        Ok(chapter_url)
-    }
-
-    fn compose_xhtml(&self) -> Result<String, Box<dyn std::error::Error>> {
-        let mut buf = Buffer::new();
-        buf.void_child(Cow::Borrowed("?xml version='1.0' encoding='utf-8'?"));
-        buf.doctype();
-        let mut html = buf
-            .html()
-            .attr(r#"xmlns="http://www.w3.org/1999/xhtml""#)
-            .attr(r#"xml:lang="en-US""#);
-        html.head().raw().write_str(
-            r#"<meta http-equiv="Content-Type" content="text/html; charset=utf-8" />"#,
-        )?;
-
-        writeln!(html.body().h1(), "{}", self.title()?)?;
-        writeln!(html.body().raw(), "{}", self.content()?)?;
-        Ok(buf.finish())
-    }
-
-    #[inline]
-    fn remove_ad(text: String) -> Result<String, Box<dyn std::error::Error>> {
-        Ok(Regex::new("<div.*?</div>")?
-            .replace_all(&text, "")
-            .to_string())
+fn validate_arg() -> Result<Args, Box<dyn std::error::Error>> {
+    let mut parsed = Args::default();
+    // --user-agent overrides it
+    parsed.config.user_agent = env::var("NOVEL2EPUB_USER_AGENT").ok().filter(|agent| !agent.is_empty());
+    let mut args = env::args().skip(1);
+    let mut footnotes = false;
+    let mut no_cache = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--skip-title-matching" => {
+                parsed.skip_title = Some(Regex::new(&arg_value(&mut args, &arg)?)?);
+            }
+            "--extra-chapters" => {
+                parsed.extra_chapters = Some(Regex::new(&arg_value(&mut args, &arg)?)?);
+            }
+            "--check-site" => parsed.check_site = true,
+            "--dry-run" => parsed.dry_run = true,
+            "--verify-links" => parsed.verify_links = true,
+            "--force-unlock" => parsed.force_unlock = true,
+            "--resume" => parsed.resume = true,
+            "--force" => parsed.force = true,
+            "--verify-as-you-go" => parsed.verify_as_you_go = true,
+            "--min-content-length" => parsed.min_content_length = Some(arg_value(&mut args, &arg)?.parse()?),
+            "--skip-short-chapters" => parsed.skip_short = true,
+            "--allow-no-cover" => parsed.allow_no_cover = true,
+            "--no-cover" => parsed.no_cover = true,
+            "--cover-from-chapter" => {
+                parsed.cover_from_chapter = match arg_value(&mut args, &arg)?.parse()? {
+                    0 => return Err(Box::new(NovelError::Other("Chapters are counted from 1".to_string()))),
+                    n => Some(n),
+                }
+            }
+            "--parallel-covers" => {
+                parsed.parallel_covers = match arg_value(&mut args, &arg)?.parse()? {
+                    0 => return Err(Box::new(NovelError::Other("--parallel-covers needs at least 1".to_string()))),
+                    workers => Some(workers),
+                }
+            }
+            "-y" | "--yes" => parsed.yes = true,
+            "--progress" => parsed.progress = true,
+            "-v" | "--verbose" => parsed.verbosity += 1,
+            "-vv" => parsed.verbosity += 2,
+            "-q" | "--quiet" => parsed.verbosity -= 1,
+            "-qq" => parsed.verbosity -= 2,
+            "--dir-name" => parsed.config.dir_name = Some(arg_value(&mut args, &arg)?),
+            "--replace" => parsed.config.replacements.push(parse_replacement(&arg_value(&mut args, &arg)?, false)?),
+            "--replace-regex" => {
+                parsed.config.replacements.push(parse_replacement(&arg_value(&mut args, &arg)?, true)?)
+            }
+            "--footnotes" => footnotes = true,
+            "--footnote-pattern" => parsed.config.footnotes = Some(Regex::new(&arg_value(&mut args, &arg)?)?),
+            "--generate-cover" => parsed.config.generate_cover = true,
+            "--cover-background" => parsed.config.cover_background = Some(arg_value(&mut args, &arg)?),
+            "--cover-foreground" => parsed.config.cover_foreground = Some(arg_value(&mut args, &arg)?),
+            "--cover-font" => parsed.config.cover_font = Some(arg_value(&mut args, &arg)?),
+            "--optimize-png" => parsed.config.optimize_png = true,
+            "--keep-raw-html" => parsed.config.keep_raw_html = true,
+            "--tls-insecure" => parsed.config.tls_insecure = true,
+            "--header" => parsed.config.headers.push(parse_header(&arg_value(&mut args, &arg)?)?),
+            "--user-agent" => parsed.config.user_agent = Some(arg_value(&mut args, &arg)?),
+            "--lang" => parsed.lang = Some(arg_value(&mut args, &arg)?),
+            "--language-detect" => parsed.detect_lang = true,
+            "--sort-by-alt-title" => parsed.sort_by_alt_title = true,
+            "--css" => {
+                let path = arg_value(&mut args, &arg)?;
+                if !std::path::Path::new(&path).is_file() {
+                    return Err(Box::new(NovelError::Other(format!("No stylesheet at '{path}'"))));
+                }
+                parsed.css = Some(path);
+            }
+            "--epub-version" => {
+                parsed.epub3 = match arg_value(&mut args, &arg)?.as_str() {
+                    "2" => false,
+                    "3" => true,
+                    version => {
+                        return Err(Box::new(NovelError::Other(format!("Unsupported EPUB version '{version}'"))));
+                    }
+                }
+            }
+            "--build-range" => {
+                let range = arg_value(&mut args, &arg)?;
+                parsed.build_range = match range.split_once('-') {
+                    Some((first, last)) if first.parse::<u64>()? <= last.parse::<u64>()? => {
+                        Some((first.parse()?, last.parse()?))
+                    }
+                    _ => return Err(Box::new(NovelError::Other(format!("Invalid range '{range}', expected <first>-<last>")))),
+                }
+            }
+            "--from-dir" => parsed.from_dir = Some(arg_value(&mut args, &arg)?),
+            "--out" => parsed.out_dir = Some(arg_value(&mut args, &arg)?),
+            "--manifest-out" => parsed.manifest_out = Some(arg_value(&mut args, &arg)?),
+            "--validate-only" => parsed.validate_only = Some(arg_value(&mut args, &arg)?),
+            "--title" => parsed.title = Some(arg_value(&mut args, &arg)?),
+            "--author" => parsed.author = Some(arg_value(&mut args, &arg)?),
+            "--cover" => parsed.cover = Some(arg_value(&mut args, &arg)?),
+            "--log-file" => parsed.log_file = Some(arg_value(&mut args, &arg)?),
+            "--log-append" => parsed.log_append = true,
+            "-o" | "--output" => parsed.output = Some(arg_value(&mut args, &arg)?),
+            "--translator" => parsed.translator = Some(arg_value(&mut args, &arg)?),
+            "--editor" => parsed.editor = Some(arg_value(&mut args, &arg)?),
+            "--epub-filename-template" => {
+                let template = arg_value(&mut args, &arg)?;
+                validate_filename_template(&template)?;
+                parsed.filename_template = Some(template);
+            }
+            "--skip-first" => parsed.skip_first = arg_value(&mut args, &arg)?.parse()?,
+            "--skip-last" => parsed.skip_last = arg_value(&mut args, &arg)?.parse()?,
+            "--series" => parsed.series = Some(arg_value(&mut args, &arg)?),
+            "--series-index" => parsed.series_index = Some(arg_value(&mut args, &arg)?.parse()?),
+            "--max-retries" => {
+                let retries = arg_value(&mut args, &arg)?.parse();
+                let retries = retries.map_err(|_| NovelError::Other(format!("{arg} takes a non-negative number")))?;
+                parsed.config.max_retries = Some(retries);
+            }
+            "--retry-base-delay" => {
+                let delay = arg_value(&mut args, &arg)?.parse();
+                let delay = delay.map_err(|_| NovelError::Other(format!("{arg} takes a non-negative number")))?;
+                parsed.config.retry_base_delay = Some(Duration::from_millis(delay));
+            }
+            "--timeout" => {
+                let secs = arg_value(&mut args, &arg)?.parse().ok().filter(|secs| *secs > 0);
+                let secs = secs.ok_or(NovelError::Other(format!("{arg} takes a positive number of seconds")))?;
+                parsed.config.timeout = Some(Duration::from_secs(secs));
+            }
+            "--concurrency" => {
+                let n = arg_value(&mut args, &arg)?.parse().ok().filter(|n| *n > 0);
+                parsed.config.concurrency = Some(n.ok_or(NovelError::Other(format!("{arg} takes a positive number")))?);
+            }
+            "--delay" => parsed.config.delay = Some(Duration::from_millis(arg_value(&mut args, &arg)?.parse()?)),
+            "--sleep-jitter" => {
+                parsed.config.sleep_jitter = Some(Duration::from_millis(arg_value(&mut args, &arg)?.parse()?));
+            }
+            "--cache-dir" => parsed.config.cache_dir = Some(arg_value(&mut args, &arg)?),
+            "--no-cache" => no_cache = true,
+            "--cache-ttl" => parsed.config.cache_ttl = Some(Duration::from_secs(arg_value(&mut args, &arg)?.parse()?)),
+            "--refresh" => parsed.config.refresh = true,
+            "--seed" => parsed.config.seed = Some(arg_value(&mut args, &arg)?.parse()?),
+            "--timeout-total" => {
+                parsed.timeout_total = Some(Duration::from_secs(arg_value(&mut args, &arg)?.parse()?));
+            }
+            "--percent-encode-filenames" => parsed.config.percent_encode_filenames = true,
+            "--format" => {
+                parsed.config.format = match arg_value(&mut args, &arg)?.as_str() {
+                    "epub" => Format::Epub,
+                    "md" => Format::Md,
+                    "txt" => Format::Txt,
+                    format => {
+                        return Err(Box::new(NovelError::Other(format!("Unknown format '{format}'"))));
+                    }
+                }
+            }
+            "--chapter-offset" => parsed.config.chapter_offset = arg_value(&mut args, &arg)?.parse()?,
+            "--json" => parsed.json = true,
+            "--metadata-json" => parsed.metadata_json = true,
+            "--single-file" => parsed.single_file = true,
+            "--compress-level" => {
+                let level: u8 = arg_value(&mut args, &arg)?.parse()?;
+                if level > 9 {
+                    return Err(Box::new(NovelError::Other(format!("'{arg}' expects a level in 0..9"))));
+                }
+                parsed.compress_level = Some(level);
+            }
+            "--preview" => parsed.preview = Some(arg_value(&mut args, &arg)?.parse()?),
+            "--start-chapter" => parsed.start_chapter = Some(arg_value(&mut args, &arg)?.parse()?),
+            "--from" => parsed.from = Some(arg_value(&mut args, &arg)?.parse()?),
+            "--to" => parsed.to = Some(arg_value(&mut args, &arg)?.parse()?),
+            "--batch" => {
+                let path = arg_value(&mut args, &arg)?;
+                parsed.urls_from_stdin |= path == "-";
+                parsed.urls.extend(read_batch(&path)?);
+            }
+            "-" => {
+                parsed.urls_from_stdin = true;
+                parsed.urls.extend(read_batch("-")?);
+            }
+            _ if arg.starts_with("--") => {
+                return Err(Box::new(NovelError::Other(format!("Unknown option '{arg}'"))));
+            }
+            _ => parsed.urls.push(arg),
+        }
+    }
+    if footnotes && parsed.config.footnotes.is_none() {
+        parsed.config.footnotes = Some(Regex::new(FOOTNOTES)?);
+    }
+    // The pages are cached only in a directory asked for, nothing removes the expired ones
+    if no_cache {
+        parsed.config.cache_dir = None;
+    }
+    if parsed.validate_only.is_some() {
+        return Ok(parsed);
+    }
+    if let (Some(from), Some(to)) = (parsed.from, parsed.to) {
+        if from > to {
+            return Err(Box::new(NovelError::Other(format!("--from {from} is after --to {to}"))));
+        }
     }
+    if parsed.from_dir.is_some() {
+        if parsed.title.is_none() || parsed.author.is_none() {
+            return Err(Box::new(NovelError::Other("--from-dir needs --title and --author".to_string())));
+        }
+        return Ok(parsed);
+    }
+    if parsed.urls.is_empty() {
+        return Err(Box::new(NovelError::Other("Please specify novel url".to_string())));
+    }
+    if parsed.output.is_some() && parsed.urls.len() > 1 {
+        return Err(Box::new(NovelError::Other("--output takes a single novel url".to_string())));
+    }
+    if parsed.manifest_out.is_some() && parsed.urls.len() > 1 {
+        return Err(Box::new(NovelError::Other("--manifest-out takes a single novel url".to_string())));
+    }
+    if parsed.config.dir_name.is_some() && parsed.urls.len() > 1 {
+        return Err(Box::new(NovelError::Other("--dir-name takes a single novel url".to_string())));
+    }
+    for url in &parsed.urls {
+        // Validate URL format
+        if site_adapter(&Novel::host(url)?).is_none() {
+            return Err(Box::new(NovelError::Other(format!(
+                "Only {} supported: '{url}'",
+                SITES.map(|(domain, _)| domain).join(", ")
+            ))));
+        }
+    }
+    Ok(parsed)
 }
 
-struct Novel {
-    workdir: String,
-    host_url: String,
-    cover: CoverPage,
-    // title_url: String,
-    chapter: Option<ChapterPage>,
-    chapter_id: u64,
-    // chapter_url: String,
+// Asks on the terminal before a possibly long download, piped runs just proceed
+fn confirm(novel: &Novel) -> Result<bool, Box<dyn std::error::Error>> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(true);
+    }
+    let count = novel
+        .chapter_count_hint()
+        .map_or("unknown number of".to_string(), |count| count.to_string());
+    eprint!(
+        "Download '{}' by {} ({count} chapters)? [y/N] ",
+        novel.cover().title()?,
+        novel.cover().author()?
+    );
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
 }
 
-impl Novel {
-    fn new(title_url: &str, workdir: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        Ok(Self {
-            workdir: workdir.to_string(),
-            host_url: Self::host(title_url)?,
-            cover: CoverPage::new(Self::request_page(title_url)?),
-            chapter: None,
-            chapter_id: 1
-        })
+// Set while a progress bar is drawn, the event lines clear it before they are printed
+static PROGRESS_SHOWN: AtomicBool = AtomicBool::new(false);
+
+// The --progress line on stderr, a bar when the cover tells the chapter count, a spinner otherwise
+struct Progress {
+    total: Option<u64>,
+    started: Instant,
+    frame: usize,
+}
+
+impl Progress {
+    const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+    const WIDTH: u64 = 30;
+
+    fn new(total: Option<u64>) -> Self {
+        Self { total, started: Instant::now(), frame: 0 }
     }
 
-    // fn clear(&self) {
-    //     let _ = std::fs::remove_dir_all(format!("{}/{}", self.workdir, self.cover().title().unwrap()));
-    // }
-
-    fn host(title_url: &str) -> Result<String, Box<dyn std::error::Error>> {
-        let re = Regex::new(r#"https*://[[:alpha:]]+\.[[:alpha:]]+\.[[:alpha:]]+"#)?;
-        let capture = re
-            .captures_iter(&title_url)
-            .next()
-            .ok_or(Box::new(NovelError::InvalidUrl))?;
-        Ok(capture[0].to_string())
-    }
-
-    fn request_page(url: &str) -> Result<Html, Box<dyn std::error::Error>> {
-        let resp = reqwest::blocking::get(url)?;
-        println!("Request url({}): '{}'", resp.status(), url);
-        if resp.status().as_u16() != 200 {
-            Err(Box::new(NovelError::Http(
-                resp.status().as_str().to_string(),
-            )))
-        } else {
-            let body = resp.text()?;
-            Ok(Html::parse_document(&body))
-        }
+    // `position` is the chapter number being worked on, `saved` how many this run saved so far
+    fn update(&mut self, position: u64, saved: u64) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 { saved as f64 / elapsed } else { 0.0 };
+        let line = match self.total {
+            // The cover's count can lag behind the chapters actually there
+            Some(total) if total >= position && total > 0 => {
+                let filled = (position * Self::WIDTH / total) as usize;
+                let eta = if rate > 0.0 {
+                    format_eta(((total - position) as f64 / rate) as u64)
+                } else {
+                    "?".to_string()
+                };
+                format!(
+                    "[{}{}] {position}/{total} {rate:.2} ch/s ETA {eta}",
+                    "#".repeat(filled),
+                    "-".repeat(Self::WIDTH as usize - filled)
+                )
+            }
+            _ => {
+                self.frame = (self.frame + 1) % Self::SPINNER.len();
+                format!("{} chapter {position}, {saved} saved, {rate:.2} ch/s", Self::SPINNER[self.frame])
+            }
+        };
+        eprint!("\r\x1b[K{line}");
+        PROGRESS_SHOWN.store(true, Ordering::Relaxed);
     }
-    fn request_data(url: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let resp = reqwest::blocking::get(url)?;
-        println!("Request url({}): '{}'", resp.status(), url);
-        if resp.status().as_u16() != 200 {
-            Err(Box::new(NovelError::Http(
-                resp.status().as_str().to_string(),
-            )))
-        } else {
-            let data = resp.bytes()?.to_vec();
-            Ok(data)
+
+    fn finish(&self) {
+        if PROGRESS_SHOWN.swap(false, Ordering::Relaxed) {
+            eprint!("\r\x1b[K");
         }
     }
+}
 
-    fn cover(&self) -> &CoverPage {
-        &self.cover
+fn format_eta(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{seconds}s"),
+        60..=3599 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60),
     }
+}
+
+#[derive(PartialEq)]
+enum Outcome {
+    Complete,
+    TimeLimited,
+}
 
-    fn chapter(&self) -> Option<&ChapterPage> {
-        self.chapter.as_ref()
+// Reports what a run would download from the cover page alone, nothing is written
+fn dry_run(novel: &Novel) -> Result<(), Box<dyn std::error::Error>> {
+    let cover = novel.cover();
+    let or_error = |result: Result<String, Box<dyn std::error::Error>>| result.unwrap_or_else(|e| format!("unknown ({e})"));
+    let count = novel.chapter_count_hint().map_or("unknown".to_string(), |count| count.to_string());
+    let dir = novel.novel_dir()?;
+    let exists = if std::path::Path::new(&dir).is_dir() { "exists" } else { "would be created" };
+    for line in [
+        format!("Title: {}", cover.title()?),
+        format!("Author: {}", or_error(cover.author())),
+        format!("Cover: {}", or_error(cover.cover_img_url())),
+        format!("First chapter: {}", or_error(cover.chapter_first_url().and_then(|href| Ok(resolve_url(novel.source_url(), &href)?)))),
+        format!("Chapters: {count}"),
+        format!("Directory: '{dir}' ({exists})"),
+    ] {
+        Event::Notice { message: &line }.emit();
     }
+    Ok(())
+}
 
-    fn next(&mut self) -> Option<&ChapterPage> {
-        if self.chapter.is_some() {
-            self.chapter_id += 1;
-            self.chapter = self._chapter_next().ok();
-        } else {
-            self.chapter = self._chapter_first().ok();
-        }
-        self.chapter.as_ref()
-    }
-
-    fn _chapter_first(&mut self) -> Result<ChapterPage, Box<dyn std::error::Error>> {
-        let url = format!("{}{}", self.host_url, self.cover.chapter_first_url()?);
-        Ok(ChapterPage::new(Self::request_page(url.as_str())?))
-    }
-
-    fn _chapter_next(&mut self) -> Result<ChapterPage, Box<dyn std::error::Error>> {
-        let url = format!(
-            "{}{}",
-            self.host_url,
-            self.chapter()
-                .as_ref()
-                .ok_or(Box::new(NovelError::Empty))?
-                .chapter_next_url()?
-        );
-        Ok(ChapterPage::new(Self::request_page(url.as_str())?))
-    }
-
-    fn cover_img_save(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let novel_dir = format!("{}/{}", self.workdir, self.cover.title()?);
-        std::fs::create_dir_all(&novel_dir)?;
-        let img = Self::request_data(self.cover.cover_img_url()?.as_str())?;
-        let img_type = self.cover.cover_img_type()?;
-        // let data = self.cover.cover_img_url()?;
-        let filepath = format!("{novel_dir}/{}.{img_type}", self.cover.title()?);
-        println!("Save to '{filepath}'");
-        let mut file = std::fs::File::create(filepath)?;
-        file.write_all(&img)?;
-        Ok(())
-    }
-
-    fn chapter_save(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let novel_dir = format!("{}/{}", self.workdir, self.cover.title()?);
-        std::fs::create_dir_all(&novel_dir)?;
-        let xhtml = self
-            .chapter
-            .as_ref()
-            .ok_or(Box::new(NovelError::Empty))?
-            .compose_xhtml()?;
-
-        let filepath = format!(
-            "{novel_dir}/{:0>8} {}.xhtml",
-            self.chapter_id,
-            self.chapter
-                .as_ref()
-                .ok_or(Box::new(NovelError::Empty))?
-                .title()?
-        );
-        println!("Save to '{filepath}'");
-        let mut file = std::fs::File::create(filepath)?;
-        write!(file, "{}", xhtml)?;
-        Ok(())
-    }
-
-    fn build_epub(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut builder = EpubBuilder::new(ZipLibrary::new()?)?;
-        builder.metadata("author", self.cover.author()?)?;
-        builder.metadata("title", self.cover.title()?)?;
-
-        let img_type = self.cover.cover_img_type()?;
-        let title = self.cover.title()?;
-        let dir = &self.workdir;
-        for entry in glob::glob(format!("{dir}/{title}/{title}.{img_type}").as_str())? {
-            let path = entry?;
-            path.as_path().file_name().unwrap().to_str().unwrap();
-            println!("Reading '{}'", path.display());
-            let content = std::fs::read(&path)?;
-            builder.add_cover_image(
-                path.as_path().file_name().unwrap().to_str().unwrap(),
-                Cursor::new(content),
-                format!("image/{img_type}"),
-            )?;
-        }
+// Resolves every selector against the cover page and the first chapter without saving anything
+fn check_site(novel: &mut Novel) -> Result<(), Box<dyn std::error::Error>> {
+    fn outcome<T>(result: Result<T, Box<dyn std::error::Error>>) -> Result<(), String> {
+        result.map(|_| ()).map_err(|e| e.to_string())
+    }
+    let cover = novel.cover();
+    let mut checks = vec![
+        ("cover title", outcome(cover.title())),
+        ("cover author", outcome(cover.author())),
+        ("cover image", outcome(cover.cover_img_url())),
+        ("cover image type", outcome(cover.cover_img_type())),
+        ("cover updated date", outcome(cover.updated_date())),
+        (
+            "cover chapter count",
+            cover.chapter_count().map(|_| ()).ok_or("not shown".to_string()),
+        ),
+        ("cover first chapter", outcome(cover.chapter_first_url())),
+    ];
+    match novel.first_chapter() {
+        Ok(chapter) => checks.extend([
+            ("chapter title", outcome(chapter.title())),
+            ("chapter content", outcome(chapter.content())),
+            ("chapter next link", outcome(chapter.chapter_next_url())),
+            (
+                "chapter is the first",
+                chapter.chapter_prev_url().map_or(Ok(()), |prev| Err(format!("previous link to '{prev}'"))),
+            ),
+        ]),
+        Err(e) => checks.push(("chapter page", Err(e.to_string()))),
+    }
+    report_checks(&checks, "site")
+}
 
-        for entry in glob::glob(format!("{dir}/{}/*.xhtml", self.cover.title()?).as_str())? {
-            let path = entry?;
-            path.as_path().file_name().unwrap().to_str().unwrap();
-            println!("Reading '{}'", path.display());
-            let chapter_name = Regex::new(r#"\d*? "#)?.replace(path.as_path().file_name().unwrap().to_str().unwrap(), "");
-            let content = std::fs::read_to_string(&path)?;
-            builder.add_content(
-                EpubContent::new(path.to_str().unwrap(), content.as_bytes())
-                    .title(chapter_name)
-                    .reftype(ReferenceType::Text),
-            )?;
-        }
-        builder.inline_toc();
-        let mut epub: Vec<u8> = vec![];
-        builder.generate(&mut epub).unwrap();
-        {
-            let mut file = std::fs::File::create(format!("{dir}/{}.epub", self.cover().title()?))?;
-            file.write_all(&epub)?;
-        }
+/// Reports the outcome of every check, failing if any did
+fn report_checks(checks: &[(&str, Result<(), String>)], what: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let failed = checks.iter().filter(|(_, result)| result.is_err()).count();
+    for (name, result) in checks {
+        Event::Check { name, error: result.as_ref().err().map(String::as_str) }.emit();
+    }
+    Event::CheckDone { passed: checks.len() - failed, failed }.emit();
+    if failed > 0 {
+        return Err(Box::new(NovelError::Other(format!("{failed} {what} checks failed"))));
+    }
+    Ok(())
+}
 
-        Ok(())
+// See `check_epub` for what is checked
+fn validate_epub(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    report_checks(&check_epub(path)?, "EPUB")
+}
 
+fn epub_options(args: &Args) -> Result<EpubOptions, Box<dyn std::error::Error>> {
+    let mut options = EpubOptions {
+        extras: args.extra_chapters.clone(),
+        epub3: args.epub3,
+        series: args.series.clone().map(|series| (series, args.series_index.unwrap_or(1.0))),
+        translator: args.translator.clone(),
+        output: args.output.clone(),
+        skip_first: args.skip_first,
+        skip_last: args.skip_last,
+        editor: args.editor.clone(),
+        lang: args.lang.clone(),
+        detect_lang: args.detect_lang,
+        no_cover: args.no_cover,
+        sort_by_alt_title: args.sort_by_alt_title,
+        css: args.css.clone(),
+        ..Default::default()
+    };
+    if let Some(level) = args.compress_level {
+        options.compress_level = level;
+    }
+    if let Some(template) = &args.filename_template {
+        options.name = template.clone();
     }
+    Ok(options)
 }
 
+/// Downloads the cover images of a batch up front with `workers` at a time, by novel url.
+/// A cover that fails is left out, its novel downloads it again and reports the error
+fn prefetch_covers(args: &Args, workers: usize) -> HashMap<String, Vec<u8>> {
+    let queue = Mutex::new(args.urls.iter());
+    let covers = Mutex::new(HashMap::new());
+    std::thread::scope(|scope| {
+        for _ in 0..workers.min(args.urls.len()) {
+            scope.spawn(|| loop {
+                let Some(url) = queue.lock().unwrap_or_else(|e| e.into_inner()).next() else {
+                    break;
+                };
+                let fetch = || -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+                    let client = HttpClient::new(&args.config)?;
+                    let host = Novel::host(url)?;
+                    let site = site_adapter(&host).ok_or(NovelError::Other(format!("Unsupported site '{host}'")))?;
+                    let cover = CoverPage::new(url, client.request_page(url)?, site);
+                    client.request_data(&cover.cover_img_url()?)
+                };
+                match fetch() {
+                    Ok(img) => {
+                        covers.lock().unwrap_or_else(|e| e.into_inner()).insert(url.clone(), img);
+                    }
+                    Err(e) => Event::Warning { message: &format!("Cover of '{url}' not prefetched: {e}") }.emit(),
+                }
+            });
+        }
+    });
+    covers.into_inner().unwrap_or_else(|e| e.into_inner())
+}
 
-fn validate_arg() -> Result<String, Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().collect();
-    match args.len() {
-        2 => {
-            let arg: String = args[1].parse()?;
-            // Validate URL format
-            let _ = Novel::host(&arg)?;
-            if Regex::new(r#"lightnovelworld\.com"#)?.is_match(&arg) {
-                Ok(arg)
-            } else {
-                Err(Box::new(NovelError::Other("Only the lightnovelworld.com is supported".to_string())))
-            }
-        },
-        _ => {
-            Err(Box::new(NovelError::Other("Please specify novel url".to_string())))
-        },
+// No scraping at all, the local pages go through the same title page and EPUB assembly
+fn import(dir: &str, args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let title = args.title.as_deref().unwrap_or_default();
+    let author = args.author.as_deref().unwrap_or_default();
+    let cover_img_type = match &args.cover {
+        Some(cover) => Some(
+            std::path::Path::new(cover)
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .ok_or(Box::new(NovelError::Image))?,
+        ),
+        None => None,
+    };
+    let novel = Novel::from_metadata(title, author, cover_img_type, args.workdir(), args.config.clone())?;
+    let chapters = novel.import(dir, args.cover.as_deref())?;
+    if args.cover.is_none() && novel.config().generate_cover {
+        novel.cover_generate()?;
     }
+    novel.write_checksums()?;
+    let epub = novel.build_epub(&epub_options(args)?)?;
+    Event::Done { chapters, skipped: 0, epub: Some(&epub) }.emit();
+    Ok(())
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let url = validate_arg()?;
-    let mut novel = Novel::new(&url, "novel")?;
+// Follows the next links without saving anything. The pages are still fetched in full,
+// the next link is only found in the body
+fn verify_links(novel: &mut Novel) -> Result<(), Box<dyn std::error::Error>> {
+    let mut chain = 0;
+    let mut last: Option<String> = None;
+    for chapter in novel.iter_chapters() {
+        let chapter = chapter.map_err(|e| match &last {
+            Some(url) => NovelError::Other(format!("The chain breaks after {chain} chapters, following '{url}': {e}")),
+            None => NovelError::Other(format!("The first chapter can't be loaded: {e}")),
+        })?;
+        chain += 1;
+        last = Some(chapter.url().to_string());
+    }
+    let last = last.unwrap_or_default();
+    Event::Notice { message: &format!("The chain of {chain} chapters is continuous, the last one is '{last}'") }.emit();
+    Ok(())
+}
+
+fn run(
+    url: &str,
+    args: &Args,
+    deadline: Option<Instant>,
+    cover: Option<Vec<u8>>,
+) -> Result<Outcome, Box<dyn std::error::Error>> {
+    let mut novel = Novel::new(url, args.workdir(), args.config.clone())?;
+    if args.check_site {
+        check_site(&mut novel)?;
+        return Ok(Outcome::Complete);
+    }
+    if args.verify_links {
+        verify_links(&mut novel)?;
+        return Ok(Outcome::Complete);
+    }
+    if args.dry_run {
+        dry_run(&novel)?;
+        return Ok(Outcome::Complete);
+    }
+    // Build only, from the chapters saved by earlier runs
+    if let Some((first, last)) = args.build_range {
+        let dir = novel.novel_dir()?;
+        if !std::path::Path::new(&dir).is_dir() {
+            return Err(Box::new(NovelError::Other(format!("No saved chapters in '{dir}'"))));
+        }
+        let mut options = epub_options(args)?;
+        options.name = format!("{} ({first}-{last})", options.name);
+        options.first_chapter = Some(first);
+        options.last_chapter = Some(last);
+        let epub = novel.build_epub(&options)?;
+        Event::Done { chapters: 0, skipped: 0, epub: Some(&epub) }.emit();
+        return Ok(Outcome::Complete);
+    }
 
     // println!("host - {}", Novel::host(url).unwrap());
     // println!("name - {}", novel.cover().title()?);
@@ -420,14 +646,343 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // println!("cover_url - {}", novel.cover().cover_img_url()?);
     // println!("chapter_url - {}", novel.cover().chapter_first_url()?);
 
+    if let Some(count) = novel.chapter_count_hint() {
+        Event::Notice { message: &format!("The novel has {count} chapters") }.emit();
+    }
+    if !args.yes && !confirm(&novel)? {
+        return Ok(Outcome::Complete);
+    }
+
     // novel.clear();
-    novel.cover_img_save()?;
-    
+    let lock = novel.lock(args.force_unlock)?;
+    if let (Some(previous), Some(count)) = (novel.record_chapter_count()?, novel.chapter_count_hint()) {
+        if count > previous {
+            Event::Notice { message: &format!("{} new chapters since the last run", count - previous) }.emit();
+        }
+    }
+    let cover = match args.cover_from_chapter {
+        _ if args.no_cover => Ok(()),
+        Some(n) => novel.cover_from_chapter(n),
+        None => novel.cover_img_save(cover),
+    };
+    match cover {
+        Err(e) if novel.config().generate_cover => {
+            Event::Warning { message: &format!("Generating a cover: {e}") }.emit();
+            novel.cover_generate()?;
+        }
+        Err(e) if args.allow_no_cover => {
+            Event::Warning { message: &format!("Continue without a cover: {e}") }.emit();
+        }
+        result => result?,
+    }
+    // Without a record of the last run the chapters are walked from the first one, the saved ones are
+    // still fetched for their next link but not written again
+    if args.resume {
+        novel.resume()?;
+    }
+    let skip_saved = !args.force;
+
     // novel.next();
     // novel.chapter_save()?;
-    while novel.next().is_some() {
-        novel.chapter_save()?;
+    let mut saved = 0;
+    let mut skipped = 0;
+    let mut outcome = Outcome::Complete;
+    // The site's number and url of the last chapter, to tell when link following skipped some
+    let mut last_numbered: Option<(u64, String)> = None;
+    // Number, title, url and file of the chapters saved by this run, for --manifest-out
+    let mut manifest = vec![];
+    let started = Utc::now();
+    if let Some(start) = args.start_chapter.max(args.from) {
+        Event::Notice { message: &format!("Following the links to chapter {start}, the ones before it aren't saved") }.emit();
+    }
+    // Only drawn on a terminal, it would be noise in a redirected log
+    let mut progress = (args.progress && std::io::stdout().is_terminal() && std::io::stderr().is_terminal())
+        .then(|| Progress::new(args.to.or(novel.chapter_count_hint())));
+    loop {
+        // Also while only walking or skipping chapters, a long run of those shouldn't leave the lock stale
+        lock.refresh()?;
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            Event::Notice { message: "Total timeout reached, building from the chapters saved so far" }.emit();
+            outcome = Outcome::TimeLimited;
+            break;
+        }
+        if args.preview.is_some_and(|n| saved + skipped >= n) {
+            break;
+        }
+        // Stops before fetching the chapter after the last one asked for
+        if args.to.is_some_and(|to| novel.chapter().is_some() && novel.chapter_number().is_ok_and(|number| number >= to)) {
+            break;
+        }
+        if novel.next().is_none() {
+            if let Some(e) = novel.take_error() {
+                return Err(e);
+            }
+            break;
+        }
+        let chapter = novel.chapter().ok_or(Box::new(NovelError::Empty))?;
+        if let Some(progress) = &mut progress {
+            progress.update(novel.chapter_number()?, saved);
+        }
+        if let Some(number) = title_chapter_number(&chapter.title()?) {
+            if let Some((previous, previous_url)) = &last_numbered {
+                if number > previous + 1 {
+                    Event::ChapterGap { previous: *previous, found: number, previous_url, url: chapter.url() }.emit();
+                }
+            }
+            last_numbered = Some((number, chapter.url().to_string()));
+        }
+        // Only followed for the link to the next one
+        let start = args.start_chapter.max(args.from);
+        if start.is_some_and(|start| novel.chapter_number().is_ok_and(|number| number < start)) {
+            continue;
+        }
+        if let Some(re) = &args.skip_title {
+            let title = chapter.title()?;
+            if re.is_match(&title) {
+                Event::ChapterSkipped {
+                    id: novel.chapter_number()?,
+                    title: &title,
+                    reason: &format!("matches '{}'", re.as_str()),
+                }
+                .emit();
+                skipped += 1;
+                continue;
+            }
+        }
+        if let Some(min) = args.min_content_length {
+            // Locked chapters come as a short "subscribe to read" stub rather than empty
+            let length = chapter.text()?.chars().count();
+            if length < min {
+                let title = chapter.title()?;
+                let reason = format!("only {length} characters of text");
+                if args.skip_short {
+                    Event::ChapterSkipped { id: novel.chapter_number()?, title: &title, reason: &reason }.emit();
+                    skipped += 1;
+                    continue;
+                }
+                Event::Warning { message: &format!("Chapter '{title}' looks like a stub, {reason}") }.emit();
+            }
+        }
+        if skip_saved && novel.chapter_saved()? {
+            Event::ChapterSkipped { id: novel.chapter_number()?, title: &chapter.title()?, reason: "saved already" }.emit();
+            skipped += 1;
+            continue;
+        }
+        let mut path = novel.chapter_save()?;
+        if args.verify_as_you_go {
+            if let Err(e) = novel.verify_saved() {
+                Event::Warning { message: &format!("{e}, downloading it again") }.emit();
+                novel.refetch()?;
+                path = novel.chapter_save()?;
+                novel.verify_saved()?;
+            }
+        }
+        if args.manifest_out.is_some() {
+            let chapter = novel.chapter().ok_or(Box::new(NovelError::Empty))?;
+            manifest.push((novel.chapter_number()?, chapter.title()?, chapter.url().to_string(), path));
+        }
+        saved += 1;
+        if let Some(progress) = &mut progress {
+            progress.update(novel.chapter_number()?, saved);
+        }
+    }
+    if let Some(progress) = &progress {
+        progress.finish();
+    }
+    novel.write_checksums()?;
+    let mut options = epub_options(args)?;
+    if args.preview.is_some() {
+        options.name = format!("{} (preview)", options.name);
+        options.last_chapter = Some(novel.chapter_number()?);
+    }
+    if args.from.is_some() || args.to.is_some() {
+        let to = args.to.map(|to| to.to_string()).unwrap_or_default();
+        options.name = format!("{} ({}-{to})", options.name, args.from.unwrap_or(1));
+        options.first_chapter = args.from.or(options.first_chapter);
+        options.last_chapter = match (args.to, options.last_chapter) {
+            (Some(to), Some(last)) => Some(to.min(last)),
+            (to, last) => to.or(last),
+        };
+    }
+    let epub = match novel.config().format {
+        Format::Epub => Some(novel.build_epub(&options)?),
+        Format::Txt => Some(novel.build_txt(&options)?),
+        Format::Md if args.single_file => Some(novel.build_markdown(&options)?),
+        Format::Md => None,
+    };
+    Event::Done { chapters: saved, skipped, epub: epub.as_deref() }.emit();
+    if args.metadata_json {
+        let path = novel.write_metadata_json(epub.as_deref())?;
+        Event::Notice { message: &format!("Metadata written to '{path}'") }.emit();
     }
-    novel.build_epub()?;
+    if let Some(path) = &args.manifest_out {
+        write_manifest(path, &novel, &manifest, (saved, skipped), epub.as_deref(), started)?;
+    }
+    Ok(outcome)
+}
+
+/// Writes what a run did as JSON, for the scripts driving it: the novel, the chapters saved
+/// with their urls and SHA-256 hashes, the counts and when it started and finished
+fn write_manifest(
+    path: &str,
+    novel: &Novel,
+    chapters: &[(u64, String, String, String)],
+    (saved, skipped): (u64, u64),
+    epub: Option<&str>,
+    started: DateTime<Utc>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut entries = vec![];
+    for (number, title, url, file) in chapters {
+        entries.push(format!(
+            r#"{{"number":{number},"title":{},"url":{},"path":{},"sha256":"{}"}}"#,
+            json_string(title),
+            json_string(url),
+            json_string(file),
+            sha256_hex(&std::fs::read(file)?)
+        ));
+    }
+    let manifest = format!(
+        r#"{{"title":{},"author":{},"source_url":{},"started":"{}","finished":"{}","saved":{saved},"skipped":{skipped},"epub":{},"chapters":[{}]}}"#,
+        json_string(&novel.cover().title()?),
+        json_string(&novel.cover().author()?),
+        json_string(novel.source_url()),
+        started.to_rfc3339_opts(SecondsFormat::Secs, true),
+        Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        epub.map_or("null".to_string(), json_string),
+        entries.join(",")
+    );
+    std::fs::write(path, manifest + "\n")?;
     Ok(())
 }
+
+// The console logger, every line it lets through also goes to the `--log-file`
+struct Logger {
+    console: env_logger::Logger,
+    file: Option<Mutex<std::fs::File>>,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.console.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.console.matches(record) {
+            return;
+        }
+        self.console.log(record);
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+            // Losing the log shouldn't stop the download
+            let _ = writeln!(file, "{} {}", chrono::Local::now().format("%Y-%m-%d %H:%M:%S"), record.args());
+        }
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap_or_else(|e| e.into_inner()).flush();
+        }
+    }
+}
+
+fn init_logger(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let level = match args.verbosity {
+        i8::MIN..=-2 => LevelFilter::Error,
+        -1 => LevelFilter::Warn,
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let mut logger = env_logger::Builder::new();
+    // Only this crate by default, the dependencies are chatty at the debug level
+    logger.filter_module("novel2epub", level);
+    if let Ok(filters) = env::var("RUST_LOG") {
+        logger.parse_filters(&filters);
+    }
+    // Keeps stdout clean for whatever the urls are piped from or the book is piped to
+    let target = if args.output.as_deref() == Some("-") || args.urls_from_stdin {
+        Target::Stderr
+    } else {
+        Target::Stdout
+    };
+    logger
+        .target(target)
+        .format(|buf, record| {
+            if PROGRESS_SHOWN.swap(false, Ordering::Relaxed) {
+                eprint!("\r\x1b[K");
+            }
+            writeln!(buf, "{}", record.args())
+        });
+    let file = match &args.log_file {
+        Some(path) => Some(Mutex::new(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(args.log_append)
+                .truncate(!args.log_append)
+                .open(path)?,
+        )),
+        None => None,
+    };
+    let console = logger.build();
+    log::set_max_level(console.filter());
+    log::set_boxed_logger(Box::new(Logger { console, file }))?;
+    Ok(())
+}
+
+fn try_main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = validate_arg()?;
+    JSON_EVENTS.store(args.json, Ordering::Relaxed);
+    init_logger(&args)?;
+    let deadline = args.timeout_total.map(|timeout| Instant::now() + timeout);
+    if let Some(epub) = &args.validate_only {
+        return validate_epub(epub);
+    }
+    if let Some(dir) = &args.from_dir {
+        return import(dir, &args);
+    }
+    if let [url] = args.urls.as_slice() {
+        if run(url, &args, deadline, None)? == Outcome::TimeLimited {
+            std::process::exit(EXIT_TIME_LIMITED);
+        }
+        return Ok(());
+    }
+    // A failed novel shouldn't stop the rest of the batch
+    let mut failed = 0;
+    let mut succeeded = 0;
+    let mut outcome = Outcome::Complete;
+    let mut covers = match args.parallel_covers {
+        Some(workers) if !args.no_cover && !args.dry_run => prefetch_covers(&args, workers),
+        _ => HashMap::new(),
+    };
+    for url in &args.urls {
+        match run(url, &args, deadline, covers.remove(url)) {
+            Ok(Outcome::Complete) => succeeded += 1,
+            Ok(Outcome::TimeLimited) => {
+                outcome = Outcome::TimeLimited;
+                break;
+            }
+            Err(e) => {
+                Event::NovelFailed { url, error: &e.to_string() }.emit();
+                failed += 1;
+            }
+        }
+    }
+    Event::BatchDone { succeeded, failed }.emit();
+    if failed > 0 {
+        return Err(Box::new(NovelError::Other(format!("{failed} of {} novels failed", args.urls.len()))));
+    }
+    if outcome == Outcome::TimeLimited {
+        std::process::exit(EXIT_TIME_LIMITED);
+    }
+    Ok(())
+}
+
+// Returning the error from `main` would print its Debug form, the message is what helps
+fn main() {
+    if let Err(e) = try_main() {
+        eprintln!("Error: {e}");
+        std::process::exit(1);
+    }
+}
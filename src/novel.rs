@@ -0,0 +1,1375 @@
+//! A novel being fetched chapter by chapter into its directory
+
+use crate::{
+    epub::xml_escape,
+    error::NovelError,
+    event::{json_string, Event},
+    http::HttpClient,
+    pages::{
+        check_xml, compose_chapter_markdown, compose_chapter_text, compose_chapter_xhtml, extract_footnotes, is_placeholder, parse_selector,
+        replace_text, site_adapter, ChapterPage, CoverPage,
+    },
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use regex::Regex;
+use scraper::Html;
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    io::Write as OWrite,
+    sync::mpsc::{self, Receiver},
+    time::{Duration, SystemTime},
+};
+
+// Leaves room for the chapter number prefix and the extension within the usual 255 bytes limit
+const MAX_FILENAME_BYTES: usize = 200;
+
+// Back-to-back requests get an IP blocked for a while, the jitter defaults to half the delay
+const DELAY: Duration = Duration::from_millis(500);
+
+const LOCK_FILE: &str = ".novel2epub.lock";
+// Hashes of the files saved to the novel directory
+const CHECKSUMS_FILE: &str = "SHA256SUMS";
+
+// Chapter images are saved under this subdirectory of the novel, and the EPUB
+pub(crate) const IMAGES_DIR: &str = "images";
+
+// Chapter count advertised by the cover at the last run of an ongoing novel
+const CHAPTER_COUNT_FILE: &str = ".chapter-count";
+
+// Id and url of the last saved chapter and the url of the next one, where `--resume` picks up
+const RESUME_FILE: &str = ".resume";
+
+// Number, url and title of each saved chapter, tab separated. A chapter saved again is listed
+// again, the last line of a number wins
+const CHAPTERS_FILE: &str = ".chapters";
+
+// How many previous links are followed looking for the first chapter, a read button pointing at
+// the latest chapter of a long novel would otherwise cost a request per chapter
+const MAX_WALK_BACK: usize = 100;
+
+// A lock that hasn't been refreshed for this long is left over from a crashed run
+const LOCK_STALE: Duration = Duration::from_secs(10 * 60);
+
+// Device names Windows won't create a file for, whatever the extension
+const RESERVED_FILENAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1",
+    "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Removes the characters that can't be a part of a file name on Windows, NTFS or anywhere else,
+/// collapses the whitespace and caps the length, cutting on a char boundary so CJK or emoji
+/// titles stay valid UTF-8. Trailing dots and spaces go too, Windows drops them silently.
+pub(crate) fn sanitize_filename(name: &str) -> String {
+    let name: String = name.chars().filter(|c| !matches!(c, '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*')).collect();
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let mut end = 0;
+    for (i, c) in name.char_indices() {
+        if i + c.len_utf8() > MAX_FILENAME_BYTES {
+            break;
+        }
+        end = i + c.len_utf8();
+    }
+    let name = name[..end].trim_end_matches([' ', '.']);
+    if name.is_empty() {
+        return "untitled".to_string();
+    }
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_FILENAMES.iter().any(|reserved| stem.eq_ignore_ascii_case(reserved)) {
+        format!("{name}_")
+    } else {
+        name.to_string()
+    }
+}
+
+/// Percent-encodes everything but ASCII for filesystems that can't store it,
+/// the length cap is applied to the encoded form without splitting a char.
+fn encode_filename(name: &str) -> String {
+    let mut encoded = String::new();
+    for c in name.chars() {
+        let piece = if c.is_ascii() && c != '%' {
+            c.to_string()
+        } else {
+            c.to_string().bytes().map(|b| format!("%{b:02X}")).collect()
+        };
+        if encoded.len() + piece.len() > MAX_FILENAME_BYTES {
+            break;
+        }
+        encoded.push_str(&piece);
+    }
+    encoded
+}
+
+/// The file extension matching the image format, told by the leading magic bytes
+fn image_type(data: &[u8]) -> Option<&'static str> {
+    match data {
+        [0x89, b'P', b'N', b'G', ..] => Some("png"),
+        [0xff, 0xd8, 0xff, ..] => Some("jpeg"),
+        [b'G', b'I', b'F', b'8', ..] => Some("gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("webp"),
+        _ => None,
+    }
+}
+
+/// SHA-256 of `data` as lowercase hex, after FIPS 180-4
+pub fn sha256_hex(data: &[u8]) -> String {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+        0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+        0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+        0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+        0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+        0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+        0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+    ];
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+    h.iter().map(|word| format!("{word:08x}")).collect()
+}
+
+/// Re-deflates the image data of a PNG at the highest level into a single IDAT chunk.
+/// None if it isn't a PNG or doesn't get any smaller
+fn optimize_png(data: &[u8]) -> Option<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    let mut chunks = vec![];
+    let mut rest = data.strip_prefix(SIGNATURE)?;
+    while rest.len() >= 12 {
+        let len = u32::from_be_bytes(rest[..4].try_into().ok()?) as usize;
+        let kind = rest.get(4..8)?;
+        let body = rest.get(8..8 + len)?;
+        chunks.push((kind, body));
+        rest = rest.get(12 + len..)?;
+    }
+    let idat: Vec<u8> = chunks.iter().filter(|(kind, _)| kind == b"IDAT").flat_map(|(_, body)| body.to_vec()).collect();
+    let pixels = miniz_oxide::inflate::decompress_to_vec_zlib(&idat).ok()?;
+    let idat = miniz_oxide::deflate::compress_to_vec_zlib(&pixels, 10);
+
+    let mut png = SIGNATURE.to_vec();
+    let mut write_chunk = |kind: &[u8], body: &[u8]| {
+        png.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        png.extend_from_slice(kind);
+        png.extend_from_slice(body);
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(kind);
+        crc.update(body);
+        png.extend_from_slice(&crc.finalize().to_be_bytes());
+    };
+    let mut idat_written = false;
+    for (kind, body) in &chunks {
+        match *kind {
+            b"IDAT" if idat_written => {}
+            b"IDAT" => {
+                write_chunk(kind, &idat);
+                idat_written = true;
+            }
+            _ => write_chunk(kind, body),
+        }
+    }
+    (png.len() < data.len()).then_some(png)
+}
+
+// Query parameters that only track the visitor and differ from run to run
+const TRACKING_PARAMS: [&str; 8] = ["fbclid", "gclid", "ref", "sid", "session", "sessionid", "_ga", "phpsessid"];
+
+/// Normalized form of a url for comparing and keying: the tracking parameters and
+/// the fragment are dropped and the remaining parameters sorted.
+/// The original url is still the one to request, as some parameters can matter.
+pub(crate) fn canonicalize_url(url: &str) -> String {
+    let Ok(mut parsed) = url::Url::parse(url) else {
+        return url.to_string();
+    };
+    let mut params: Vec<(String, String)> = parsed
+        .query_pairs()
+        .filter(|(name, _)| {
+            let name = name.to_lowercase();
+            !name.starts_with("utm_") && !TRACKING_PARAMS.contains(&name.as_str())
+        })
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    params.sort();
+    parsed.set_fragment(None);
+    if params.is_empty() {
+        parsed.set_query(None);
+    } else {
+        parsed.query_pairs_mut().clear().extend_pairs(params);
+    }
+    parsed.to_string()
+}
+
+const IMG_TAG: &str = r#"<img\b[^>]*>"#;
+
+// The remote url of an image tag on the page at `base`, data-src wins over the placeholder a
+// lazy loader puts in src. Relative to the page like in a browser, inline data: images are left alone
+fn image_src(base: &str, tag: &str) -> Option<String> {
+    let src_re = Regex::new(r#"\s(data-src|src)="([^"]+)""#).ok()?;
+    let mut srcs: Vec<_> = src_re.captures_iter(tag).map(|c| (c[1].to_string(), c[2].to_string())).collect();
+    srcs.sort_by_key(|(attr, _)| attr != "data-src");
+    resolve_url(base, &srcs.into_iter().next()?.1.replace("&amp;", "&")).ok()
+}
+
+/// The url a link on the page at `base` points to, resolved the way a browser does it:
+/// absolute, protocol relative, root relative and relative hrefs alike. The fragment is
+/// dropped, links to anything but a web page are refused
+pub fn resolve_url(base: &str, href: &str) -> Result<String, NovelError> {
+    let invalid = || NovelError::Other(format!("Invalid link '{href}' on '{base}'"));
+    let mut url = url::Url::parse(base).and_then(|base| base.join(href.trim())).map_err(|_| invalid())?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(invalid());
+    }
+    url.set_fragment(None);
+    Ok(url.to_string())
+}
+
+#[derive(Clone, Default, PartialEq)]
+pub enum Format {
+    #[default]
+    Epub,
+    // Markdown chapters for tracking the text in git, no EPUB is built
+    Md,
+    // Plain text chapters, joined into a single text file instead of the EPUB
+    Txt,
+}
+
+impl Format {
+    // Of the saved chapter files
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            Format::Epub => "xhtml",
+            Format::Md => "md",
+            Format::Txt => "txt",
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Config {
+    pub format: Format,
+    pub percent_encode_filenames: bool,
+    // Added to the chapter number shown in file names and logs, e.g. -1 when the site starts at a prologue 0
+    pub chapter_offset: i64,
+    // Skip certificate verification, only for mirrors with broken TLS setups
+    pub tls_insecure: bool,
+    // Sent with every request, e.g. a Referer some mirrors insist on
+    pub headers: Vec<(String, String)>,
+    // Instead of `USER_AGENT`, a User-Agent in `headers` wins over both
+    pub user_agent: Option<String>,
+    // Also save the fetched chapter pages untouched, under "raw/" in the novel directory
+    pub keep_raw_html: bool,
+    pub optimize_png: bool,
+    // Generate an SVG cover when the novel has none, in these colors and font family
+    pub generate_cover: bool,
+    pub cover_background: Option<String>,
+    pub cover_foreground: Option<String>,
+    pub cover_font: Option<String>,
+    // Inline notes matching this are collected at the end of each chapter
+    pub footnotes: Option<Regex>,
+    // Find and replace over the chapter text, in the order given
+    pub replacements: Vec<(Regex, String)>,
+    // Name of the novel directory instead of the title with the url hash
+    pub dir_name: Option<String>,
+    // Override `MAX_RETRIES` and `RETRY_BASE_DELAY`
+    pub max_retries: Option<u32>,
+    pub retry_base_delay: Option<Duration>,
+    // Overrides `REQUEST_TIMEOUT`, and `CONNECT_TIMEOUT` when shorter
+    pub timeout: Option<Duration>,
+    // Pause before each chapter after the first, the jitter adds a random 0..jitter on top.
+    // Override `DELAY` and half of it
+    pub delay: Option<Duration>,
+    pub sleep_jitter: Option<Duration>,
+    pub seed: Option<u64>,
+    // Chapters fetched ahead at once when the chapter urls count up, otherwise the next one only
+    pub concurrency: Option<usize>,
+    // Fetched pages are kept here for `cache_ttl`, `CACHE_TTL` by default, none without a directory.
+    // `refresh` fetches them all again, still caching them
+    pub cache_dir: Option<String>,
+    pub cache_ttl: Option<Duration>,
+    pub refresh: bool,
+}
+
+// Status and body of a page fetched by another thread, or why it failed
+type Fetched = Result<(u16, String), NovelError>;
+
+// A chapter being fetched ahead, `id` is the `chapter_id` it's expected to have
+struct ChapterTask {
+    id: u64,
+    url: String,
+    fetched: Receiver<Fetched>,
+}
+
+/// The url with its last number moved by `step`, e.g. ".../chapter-7" to ".../chapter-9" by 2.
+/// A zero-padded number keeps its width, ".../chapter-007" becomes ".../chapter-009"
+fn shift_url_number(url: &str, step: i64) -> Option<String> {
+    let re = Regex::new(r#"(\d+)(\D*)$"#).ok()?;
+    let captures = re.captures(url)?;
+    let number = captures.get(1)?;
+    let shifted = number.as_str().parse::<i64>().ok()?.checked_add(step).filter(|n| *n >= 0)?;
+    let width = match number.as_str().starts_with('0') {
+        true => number.as_str().len(),
+        false => 0,
+    };
+    Some(format!("{}{shifted:0width$}{}", &url[..number.start()], &captures[2]))
+}
+
+pub struct Chapters<'a> {
+    novel: &'a mut Novel,
+    done: bool,
+}
+
+impl Iterator for Chapters<'_> {
+    type Item = Result<ChapterPage, Box<dyn std::error::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let chapter = match self.novel.chapter() {
+            None => self.novel._chapter_first(),
+            Some(chapter) if chapter.chapter_next_url().is_err() => {
+                self.done = true;
+                return None;
+            }
+            Some(_) => {
+                self.novel.chapter_id += 1;
+                self.novel._chapter_next()
+            }
+        };
+        match chapter {
+            Ok(chapter) => {
+                self.novel.chapter = Some(chapter.clone());
+                self.novel.prefetch();
+                Some(Ok(chapter))
+            }
+            Err(e) => {
+                self.done = true;
+                match e.downcast_ref::<NovelError>() {
+                    Some(e) if e.is_end() => {
+                        Event::Notice { message: &format!("No more chapters: {e}") }.emit();
+                        None
+                    }
+                    _ => Some(Err(e)),
+                }
+            }
+        }
+    }
+}
+
+pub struct NovelLock {
+    path: String,
+    file: std::fs::File,
+}
+
+impl NovelLock {
+    fn acquire(novel_dir: &str, force: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = format!("{novel_dir}/{LOCK_FILE}");
+        if let Ok(meta) = std::fs::metadata(&path) {
+            let age = meta.modified()?.elapsed().unwrap_or_default();
+            if !force && age < LOCK_STALE {
+                return Err(Box::new(NovelError::Locked(path)));
+            }
+            Event::Notice { message: &format!("Remove lock '{path}'") }.emit();
+            std::fs::remove_file(&path)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|_| Box::new(NovelError::Locked(path.clone())))?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self { path, file })
+    }
+
+    pub fn refresh(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.file.set_modified(SystemTime::now())?;
+        Ok(())
+    }
+}
+
+impl Drop for NovelLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+pub struct Novel {
+    pub(crate) config: Config,
+    client: HttpClient,
+    pub(crate) workdir: String,
+    pub(crate) cover: CoverPage,
+    // title_url: String,
+    pub(crate) source_url: String,
+    chapter: Option<ChapterPage>,
+    chapter_id: u64,
+    // chapter_url: String,
+    // Canonical urls of the chapters followed so far
+    visited: HashSet<String>,
+    // Behind a RefCell as images are paced from `chapter_save`, which only borrows the novel
+    rng: RefCell<StdRng>,
+    // The pages after the current chapter, in order, fetched in the background while the current
+    // one is saved. More than one only with `concurrency`, as long as the urls count up
+    prefetched: VecDeque<ChapterTask>,
+    // Turned off once a next link doesn't go where the counting up predicted
+    predict_urls: bool,
+    // Where `resume` left off, the first chapter fetched instead of the cover's
+    resume_url: Option<String>,
+    // Why `next` stopped short of the end of the novel, see `take_error`
+    error: Option<Box<dyn std::error::Error>>,
+    // Local path of each chapter image url downloaded so far, None for the ones that failed
+    images: RefCell<HashMap<String, Option<String>>>,
+    // Hashes of the files saved by this run by their path in the novel directory, not yet in `CHECKSUMS_FILE`
+    checksums: RefCell<BTreeMap<String, String>>,
+}
+
+impl Novel {
+    pub fn new(title_url: &str, workdir: &str, config: Config) -> Result<Self, Box<dyn std::error::Error>> {
+        if config.tls_insecure {
+            Event::Warning { message: "WARNING: TLS certificate verification is disabled, the connection can be intercepted" }.emit();
+        }
+        let client = HttpClient::new(&config)?;
+        let host = Self::host(title_url)?;
+        let site = site_adapter(&host).ok_or(Box::new(NovelError::Other(format!("Unsupported site '{host}'"))))?;
+        let cover = CoverPage::new(title_url, client.request_page(title_url)?, site);
+        Ok(Self::with_cover(cover, title_url, workdir, config, client))
+    }
+
+    /// A novel without a site behind it, see `import`
+    pub fn from_metadata(
+        title: &str,
+        author: &str,
+        cover_img_type: Option<&str>,
+        workdir: &str,
+        config: Config,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let cover = CoverPage::from_metadata(title, author, cover_img_type);
+        let client = HttpClient::new(&config)?;
+        Ok(Self::with_cover(cover, "", workdir, config, client))
+    }
+
+    fn with_cover(
+        cover: CoverPage,
+        source_url: &str,
+        workdir: &str,
+        config: Config,
+        client: HttpClient,
+    ) -> Self {
+        Self {
+            cover,
+            client,
+            workdir: workdir.to_string(),
+            source_url: source_url.to_string(),
+            chapter: None,
+            chapter_id: 1,
+            visited: HashSet::new(),
+            rng: RefCell::new(match config.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            }),
+            prefetched: VecDeque::new(),
+            predict_urls: true,
+            resume_url: None,
+            error: None,
+            images: RefCell::new(HashMap::new()),
+            checksums: RefCell::new(BTreeMap::new()),
+            config,
+        }
+    }
+
+    // fn clear(&self) {
+    //     let _ = std::fs::remove_dir_all(format!("{}/{}", self.workdir, self.cover().title().unwrap()));
+    // }
+
+    /// The scheme, host and port of `title_url`, e.g. "https://www.lightnovelworld.com"
+    pub fn host(title_url: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let url = url::Url::parse(title_url.trim()).map_err(|_| NovelError::InvalidUrl)?;
+        let host = url.host_str().filter(|_| matches!(url.scheme(), "http" | "https"));
+        let host = host.ok_or(NovelError::InvalidUrl)?;
+        Ok(match url.port() {
+            Some(port) => format!("{}://{host}:{port}", url.scheme()),
+            None => format!("{}://{host}", url.scheme()),
+        })
+    }
+
+    // Waited before every chapter and image request but the first chapter
+    fn pause(&self) -> Duration {
+        let delay = self.config.delay.unwrap_or(DELAY);
+        let jitter = self.config.sleep_jitter.unwrap_or(delay / 2);
+        delay + jitter.mul_f64(self.rng.borrow_mut().gen::<f64>())
+    }
+
+    // None before a page coming from the cache, the site isn't asked for it
+    fn pause_before(&self, url: &str) -> Duration {
+        if self.client.is_cached(url) {
+            Duration::ZERO
+        } else {
+            self.pause()
+        }
+    }
+
+    // Starts fetching the chapter after the current one, one page ahead at most. With `concurrency`
+    // and chapter urls that count up, the next few are guessed and fetched at once as well.
+    // Each thread waits the usual pause first, so a single one keeps the pace of the requests the same
+    fn prefetch(&mut self) {
+        let Some(Ok(url)) = self.chapter().map(ChapterPage::next_url) else {
+            return;
+        };
+        if self.visited.contains(&canonicalize_url(&url)) {
+            return;
+        }
+        let current = self.chapter().map(|chapter| chapter.url().to_string()).unwrap_or_default();
+        let counting = self.predict_urls && shift_url_number(&url, -1).is_some_and(|previous| previous == current);
+        let workers = match self.config.concurrency {
+            Some(n) if counting => n.max(1),
+            _ => 1,
+        };
+        let mut ahead = match self.prefetched.back() {
+            Some(task) => (task.id + 1).saturating_sub(self.chapter_id).max(1),
+            None => 1,
+        };
+        while self.prefetched.len() < workers {
+            // The next link itself is fetched as it is, only the ones after it are predicted
+            let predicted = match ahead {
+                1 => Some(url.clone()),
+                _ if counting => shift_url_number(&url, ahead as i64 - 1),
+                _ => None,
+            };
+            let Some(url) = predicted else {
+                break;
+            };
+            let (sender, receiver) = mpsc::channel();
+            let client = self.client.clone();
+            let pause = self.pause_before(&url);
+            let thread_url = url.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(pause);
+                // Kept as a `NovelError` so a network failure stays one on the other side
+                let fetched = client.request_text(&thread_url).map_err(|e| match e.downcast::<NovelError>() {
+                    Ok(e) => *e,
+                    Err(e) => NovelError::Other(e.to_string()),
+                });
+                let _ = sender.send(fetched);
+            });
+            self.prefetched.push_back(ChapterTask { id: self.chapter_id + ahead, url, fetched: receiver });
+            ahead += 1;
+        }
+    }
+
+    /// Total number of chapters as advertised by the cover page, if it shows one
+    pub fn chapter_count_hint(&self) -> Option<u64> {
+        self.cover.chapter_count()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn source_url(&self) -> &str {
+        &self.source_url
+    }
+
+    pub fn cover(&self) -> &CoverPage {
+        &self.cover
+    }
+
+    pub fn chapter(&self) -> Option<&ChapterPage> {
+        self.chapter.as_ref()
+    }
+
+    // Lends the chapter out, so it can't be `Iterator::next`, `iter_chapters` is the iterator
+    #[allow(clippy::should_implement_trait)]
+    // None at the end of the novel and when a chapter fails, `take_error` tells which
+    pub fn next(&mut self) -> Option<&ChapterPage> {
+        let chapter = match self.chapter.as_ref().map(ChapterPage::chapter_next_url) {
+            // The chapter without a next link is the last one
+            Some(Err(e)) => {
+                Event::Notice { message: &format!("No more chapters: {e}") }.emit();
+                self.chapter = None;
+                return None;
+            }
+            Some(Ok(_)) => {
+                self.chapter_id += 1;
+                self._chapter_next()
+            }
+            None => match self.resume_url.take() {
+                Some(url) => self._chapter_at(&url),
+                None => self._chapter_first(),
+            },
+        };
+        self.chapter = match chapter {
+            Ok(chapter) => Some(chapter),
+            Err(e) if e.downcast_ref::<NovelError>().is_some_and(NovelError::is_end) => {
+                Event::Notice { message: &format!("No more chapters: {e}") }.emit();
+                None
+            }
+            Err(e) => {
+                self.error = Some(e);
+                None
+            }
+        };
+        self.prefetch();
+        self.chapter.as_ref()
+    }
+
+    /// Why the last `next` returned None if it wasn't the end of the novel,
+    /// e.g. a chapter that failed to download
+    pub fn take_error(&mut self) -> Option<Box<dyn std::error::Error>> {
+        self.error.take()
+    }
+
+    /// The chapters after the current one, or from the first one on a fresh novel.
+    /// Ends after the chapter without a next link, or with the error that broke the chain
+    pub fn iter_chapters(&mut self) -> Chapters<'_> {
+        Chapters { novel: self, done: false }
+    }
+
+    // Links pointing back to an already visited chapter would loop forever
+    fn visit(&mut self, url: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.visited.insert(canonicalize_url(url)) {
+            return Err(Box::new(NovelError::Loop(url.to_string())));
+        }
+        Ok(())
+    }
+
+    fn _chapter_first(&mut self) -> Result<ChapterPage, Box<dyn std::error::Error>> {
+        let chapter = self.first_chapter()?;
+        self.visit(&chapter.url)?;
+        Ok(chapter)
+    }
+
+    /// The page of the first chapter, without marking it visited
+    pub fn first_chapter(&self) -> Result<ChapterPage, Box<dyn std::error::Error>> {
+        let mut url = resolve_url(&self.source_url, &self.cover.chapter_first_url()?)?;
+        let mut chapter = self.chapter_page(&url, self.client.request_text(&url)?)?;
+        if chapter.chapter_prev_url().is_none() {
+            return Ok(chapter);
+        }
+        // On paginated covers the read button may point to the latest chapter, the first page
+        // of the chapter list leads to the real first one. Else it is walked back to
+        match self.chapter_list_first() {
+            Ok(Some(listed)) if listed.chapter_prev_url().is_none() => return Ok(listed),
+            Ok(Some(listed)) => {
+                Event::Notice { message: &format!("'{}' listed first has a previous link too", listed.url) }.emit();
+            }
+            Ok(None) => {}
+            Err(e) => Event::Warning { message: &format!("No first chapter from the chapter list: {e}") }.emit(),
+        }
+        Event::Notice { message: &format!("'{url}' is not the first chapter, following the previous links") }.emit();
+        let mut walked = HashSet::from([canonicalize_url(&url)]);
+        while let Some(prev) = chapter.chapter_prev_url() {
+            if walked.len() > MAX_WALK_BACK {
+                Event::Warning {
+                    message: &format!("Gave up on the first chapter after {MAX_WALK_BACK} previous links, starting at '{url}'"),
+                }
+                .emit();
+                break;
+            }
+            url = resolve_url(&url, &prev)?;
+            if !walked.insert(canonicalize_url(&url)) {
+                return Err(Box::new(NovelError::Loop(url)));
+            }
+            std::thread::sleep(self.pause_before(&url));
+            chapter = self.chapter_page(&url, self.client.request_text(&url)?)?;
+        }
+        Ok(chapter)
+    }
+
+    // The chapter linked first on the first page of the chapter list, see `SiteAdapter::chapter_list`
+    fn chapter_list_first(&self) -> Result<Option<ChapterPage>, Box<dyn std::error::Error>> {
+        let Some((list_url, selector_path)) = self.cover.site.chapter_list(&self.source_url) else {
+            return Ok(None);
+        };
+        std::thread::sleep(self.pause_before(&list_url));
+        let list = self.client.request_page(&list_url)?;
+        let href = list
+            .select(&parse_selector(selector_path)?)
+            .find_map(|link| link.value().attr("href"))
+            .ok_or(NovelError::Selector { path: selector_path.to_string(), page: Some(list_url.clone()) })?;
+        let url = resolve_url(&list_url, href)?;
+        std::thread::sleep(self.pause_before(&url));
+        Ok(Some(self.chapter_page(&url, self.client.request_text(&url)?)?))
+    }
+
+    fn _chapter_at(&mut self, url: &str) -> Result<ChapterPage, Box<dyn std::error::Error>> {
+        self.visit(url)?;
+        let chapter = self.chapter_page(url, self.client.request_text(url)?)?;
+        // The recorded next link of an ongoing novel is often its "coming soon" page
+        if is_placeholder(&chapter)? {
+            return Err(Box::new(NovelError::Placeholder(url.to_string())));
+        }
+        Ok(chapter)
+    }
+
+    fn _chapter_next(&mut self) -> Result<ChapterPage, Box<dyn std::error::Error>> {
+        let url = self.chapter().ok_or(Box::new(NovelError::Empty))?.next_url()?;
+        self.visit(&url)?;
+        let fetched = match self.prefetched.pop_front() {
+            Some(task) if task.id == self.chapter_id && task.url == url => task.fetched.recv()??,
+            task => {
+                // The guessed urls went astray, the pages fetched for them are dropped
+                if task.is_some() && self.config.concurrency.is_some() && self.predict_urls {
+                    Event::Notice { message: &format!("'{url}' breaks the chapter url pattern, following the links only") }.emit();
+                    self.predict_urls = false;
+                }
+                self.prefetched.clear();
+                std::thread::sleep(self.pause_before(&url));
+                self.client.request_text(&url)?
+            }
+        };
+        let chapter = self.chapter_page(&url, fetched)?;
+        if is_placeholder(&chapter)? {
+            return Err(Box::new(NovelError::Placeholder(url)));
+        }
+        Ok(chapter)
+    }
+
+    // Parses a fetched chapter, numbered as the current one so its errors tell which it is
+    fn chapter_page(&self, url: &str, (status, body): (u16, String)) -> Result<ChapterPage, Box<dyn std::error::Error>> {
+        let mut chapter = ChapterPage::new(url, HttpClient::parse_page(url, status, &body)?, body, self.cover.site);
+        chapter.number = self.chapter_number().ok();
+        Ok(chapter)
+    }
+
+    // The chapter number as the site counts it, `chapter_id` itself always starts at 1
+    pub fn chapter_number(&self) -> Result<u64, Box<dyn std::error::Error>> {
+        let number = self.chapter_id as i64 + self.config.chapter_offset;
+        if number < 0 {
+            return Err(Box::new(NovelError::Other(format!(
+                "Chapter {} gets a negative number with the offset {}",
+                self.chapter_id, self.config.chapter_offset
+            ))));
+        }
+        Ok(number as u64)
+    }
+
+    pub(crate) fn file_name(&self, name: &str) -> String {
+        if self.config.percent_encode_filenames {
+            encode_filename(name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    // Novels can share a title, so the directory name carries a short hash of the source url
+    pub fn novel_dir(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let name = match &self.config.dir_name {
+            Some(name) => self.file_name(&sanitize_filename(name)),
+            None if self.source_url.is_empty() => self.file_name(&self.cover.title()?),
+            None => format!(
+                "{}-{:08x}",
+                self.file_name(&self.cover.title()?),
+                crc32fast::hash(canonicalize_url(&self.source_url).as_bytes())
+            ),
+        };
+        Ok(format!("{}/{name}", self.workdir))
+    }
+
+    /// Remembers the advertised chapter count of an ongoing novel, returns the one of the previous run
+    pub fn record_chapter_count(&self) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+        let path = format!("{}/{CHAPTER_COUNT_FILE}", self.novel_dir()?);
+        let previous = std::fs::read_to_string(&path).ok().and_then(|count| count.trim().parse().ok());
+        if let (Some("Ongoing"), Some(count)) = (self.cover.status().as_deref(), self.cover.chapter_count()) {
+            std::fs::write(&path, format!("{count}\n"))?;
+        }
+        Ok(previous)
+    }
+
+    /// Continues after the chapter recorded by the last run, without fetching the ones before it.
+    /// The last chapter is fetched again if it had no next link yet. Without that record it goes
+    /// on from the highest numbered chapter file. False if neither was found
+    pub fn resume(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        let Ok(recorded) = std::fs::read_to_string(format!("{}/{RESUME_FILE}", self.novel_dir()?)) else {
+            return self.resume_saved();
+        };
+        let mut lines = recorded.lines();
+        let (Some(Ok(id)), Some(url), next) = (lines.next().map(str::parse), lines.next(), lines.next()) else {
+            return Err(Box::new(NovelError::Other(format!("Malformed {RESUME_FILE} in '{}'", self.novel_dir()?))));
+        };
+        let (id, url) = match next.filter(|next| !next.is_empty()) {
+            Some(next) => (id + 1, next),
+            None => (id, url),
+        };
+        self.chapter_id = id;
+        self.resume_url = Some(url.to_string());
+        Event::Notice { message: &format!("Resuming at chapter {} from '{url}'", self.chapter_number()?) }.emit();
+        Ok(true)
+    }
+
+    // The last saved chapter is fetched again for its next link, and saved again if it was cut short.
+    // Its url comes from CHAPTERS_FILE, a file not listed there can't be resumed from
+    fn resume_saved(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        let novel_dir = self.novel_dir()?;
+        let recorded = std::fs::read_to_string(format!("{novel_dir}/{CHAPTERS_FILE}")).unwrap_or_default();
+        let mut urls = HashMap::new();
+        for line in recorded.lines() {
+            let mut fields = line.splitn(3, '\t');
+            if let (Some(Ok(number)), Some(url)) = (fields.next().map(str::parse::<u64>), fields.next()) {
+                urls.insert(number, url.to_string());
+            }
+        }
+        let number_re = Regex::new(r"^(\d+) ")?;
+        let extension = format!(".{}", self.config.format.extension());
+        let last = std::fs::read_dir(&novel_dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let number = number_re.captures(&name)?[1].parse::<u64>().ok()?;
+                (name.ends_with(&extension) && urls.contains_key(&number)).then_some(number)
+            })
+            .max();
+        let Some((number, url)) = last.and_then(|number| urls.remove_entry(&number)) else {
+            return Ok(false);
+        };
+        let Ok(id) = u64::try_from(number as i64 - self.config.chapter_offset) else {
+            return Ok(false);
+        };
+        self.chapter_id = id;
+        Event::Notice {
+            message: &format!("No {RESUME_FILE} in '{novel_dir}', resuming at the last saved chapter {number} from '{url}'"),
+        }
+        .emit();
+        self.resume_url = Some(url);
+        Ok(true)
+    }
+
+    fn record_resume(&self, chapter: &ChapterPage) -> Result<(), Box<dyn std::error::Error>> {
+        let next = chapter.next_url().unwrap_or_default();
+        let path = format!("{}/{RESUME_FILE}", self.novel_dir()?);
+        std::fs::write(path, format!("{}\n{}\n{next}\n", self.chapter_id, chapter.url))?;
+        Ok(())
+    }
+
+    fn record_chapter(&self, number: u64, title: &str, url: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let path = format!("{}/{CHAPTERS_FILE}", self.novel_dir()?);
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{number}\t{url}\t{title}")?;
+        Ok(())
+    }
+
+    /// Writes the metadata of the novel as JSON next to `epub` (or the text file of `Format::Txt`),
+    /// or in the work directory without one: title, author, source url, the cover image file and the chapters saved by this and
+    /// earlier runs with their urls. Returns the path of the file
+    pub fn write_metadata_json(&self, epub: Option<&str>) -> Result<String, Box<dyn std::error::Error>> {
+        let title = self.cover.title()?;
+        let path = match epub {
+            Some(epub) if epub != "-" => std::path::Path::new(epub).with_extension("json").display().to_string(),
+            _ => format!("{}/{}.json", self.workdir, self.file_name(&title)),
+        };
+        let recorded = std::fs::read_to_string(format!("{}/{CHAPTERS_FILE}", self.novel_dir()?)).unwrap_or_default();
+        let mut chapters = BTreeMap::new();
+        for line in recorded.lines() {
+            let mut fields = line.splitn(3, '\t');
+            if let (Some(Ok(number)), Some(url), Some(title)) = (fields.next().map(str::parse::<u64>), fields.next(), fields.next()) {
+                chapters.insert(number, (url, title));
+            }
+        }
+        let entries: Vec<String> = chapters
+            .iter()
+            .map(|(number, (url, title))| {
+                format!(r#"{{"id":{number},"title":{},"source_url":{}}}"#, json_string(title), json_string(url))
+            })
+            .collect();
+        let cover = self.saved_cover_type()?.map(|img_type| format!("{}.{img_type}", self.file_name(&title)));
+        let metadata = format!(
+            r#"{{"version":1,"title":{},"author":{},"source_url":{},"cover":{},"chapter_count":{},"chapters":[{}]}}"#,
+            json_string(&title),
+            json_string(&self.cover.author()?),
+            json_string(&self.source_url),
+            cover.as_deref().map_or("null".to_string(), json_string),
+            entries.len(),
+            entries.join(",")
+        );
+        std::fs::write(&path, metadata + "\n")?;
+        Ok(path)
+    }
+
+    pub fn lock(&self, force: bool) -> Result<NovelLock, Box<dyn std::error::Error>> {
+        let novel_dir = self.novel_dir()?;
+        std::fs::create_dir_all(&novel_dir)?;
+        NovelLock::acquire(&novel_dir, force)
+    }
+
+    // Only PNGs are recompressed, and only losslessly, there is no JPEG encoder at hand
+    fn optimize_image<'a>(&self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.config.optimize_png {
+            optimize_png(data).map_or(Cow::Borrowed(data), Cow::Owned)
+        } else {
+            Cow::Borrowed(data)
+        }
+    }
+
+    /// Writes a file of the novel directory and keeps its hash for `write_checksums`
+    fn save_file(&self, filepath: &str, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(filepath, data)?;
+        let novel_dir = self.novel_dir()?;
+        let name = filepath.strip_prefix(&format!("{novel_dir}/")).unwrap_or(filepath);
+        self.checksums.borrow_mut().insert(name.to_string(), sha256_hex(data));
+        Ok(())
+    }
+
+    /// Adds the hashes of the files saved so far to `CHECKSUMS_FILE` with those of earlier runs,
+    /// in the format `sha256sum --check` reads. Done once at the end of a run, and when the novel is dropped
+    pub fn write_checksums(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.checksums.borrow().is_empty() {
+            return Ok(());
+        }
+        let path = format!("{}/{CHECKSUMS_FILE}", self.novel_dir()?);
+        let mut sums: BTreeMap<String, String> = std::fs::read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.split_once("  "))
+            .map(|(sum, name)| (name.to_string(), sum.to_string()))
+            .collect();
+        sums.append(&mut self.checksums.borrow_mut());
+        let sums: String = sums.iter().map(|(name, sum)| format!("{sum}  {name}\n")).collect();
+        std::fs::write(path, sums)?;
+        Ok(())
+    }
+
+    /// A plain SVG cover with the title and the author, for novels without a cover image
+    pub fn cover_generate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let novel_dir = self.novel_dir()?;
+        std::fs::create_dir_all(&novel_dir)?;
+        let background = self.config.cover_background.as_deref().unwrap_or("#2c3e50");
+        let foreground = self.config.cover_foreground.as_deref().unwrap_or("#ecf0f1");
+        let font = self.config.cover_font.as_deref().unwrap_or("serif");
+        // Wrapped on words to fit the width, each line escaped
+        let title = self.cover.title()?;
+        let mut lines: Vec<String> = vec![];
+        for word in title.split_whitespace() {
+            match lines.last_mut() {
+                Some(line) if line.chars().count() + word.chars().count() < 18 => {
+                    line.push(' ');
+                    line.push_str(word);
+                }
+                _ => lines.push(word.to_string()),
+            }
+        }
+        let lines: Vec<String> = lines.iter().map(|line| xml_escape(line)).collect();
+        let mut svg = format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<svg xmlns="http://www.w3.org/2000/svg" width="600" height="800" viewBox="0 0 600 800">
+  <rect width="600" height="800" fill="{background}"/>
+  <g fill="{foreground}" font-family="{font}" text-anchor="middle">
+"#,
+            background = xml_escape(background),
+            foreground = xml_escape(foreground),
+            font = xml_escape(font)
+        );
+        for (i, line) in lines.iter().enumerate() {
+            svg.push_str(&format!("    <text x=\"300\" y=\"{}\" font-size=\"48\">{line}</text>\n", 260 + i * 60));
+        }
+        svg.push_str(&format!(
+            "    <text x=\"300\" y=\"{}\" font-size=\"28\">{}</text>\n  </g>\n</svg>\n",
+            320 + lines.len() * 60,
+            xml_escape(&self.cover.author()?)
+        ));
+        let filepath = format!("{novel_dir}/{}.svg", self.file_name(&title));
+        Event::CoverSaved { path: &filepath }.emit();
+        self.save_file(&filepath, svg.as_bytes())
+    }
+
+    /// Saves the cover image, downloaded unless `prefetched` already holds it
+    pub fn cover_img_save(&self, prefetched: Option<Vec<u8>>) -> Result<(), Box<dyn std::error::Error>> {
+        let novel_dir = self.novel_dir()?;
+        std::fs::create_dir_all(&novel_dir)?;
+        let img = match prefetched {
+            Some(img) => img,
+            None => self.client.request_data(self.cover.cover_img_url()?.as_str())?,
+        };
+        let img_type = self.cover.cover_img_type()?;
+        // let data = self.cover.cover_img_url()?;
+        let filepath = format!("{novel_dir}/{}.{img_type}", self.file_name(&self.cover.title()?));
+        Event::CoverSaved { path: &filepath }.emit();
+        self.save_file(&filepath, &self.optimize_image(&img))?;
+        Ok(())
+    }
+
+    /// The extension of the saved cover image, the generated SVG one aside. It is the one of
+    /// the cover page's image unless the cover was taken from a chapter, see `cover_from_chapter`
+    pub(crate) fn saved_cover_type(&self) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let path = format!("{}/{}", self.novel_dir()?, self.file_name(&self.cover.title()?));
+        let types = self.cover.cover_img_type().into_iter().chain(["jpeg", "png", "gif", "webp"].map(String::from));
+        Ok(types.into_iter().find(|img_type| std::path::Path::new(&format!("{path}.{img_type}")).exists()))
+    }
+
+    /// Saves the largest image of chapter `n` as the cover, the chapters before it are only
+    /// followed for their next links
+    pub fn cover_from_chapter(&self, n: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let mut chapter = self.first_chapter()?;
+        chapter.number = Some(1);
+        for i in 2..=n {
+            let url = chapter.next_url()?;
+            std::thread::sleep(self.pause_before(&url));
+            chapter = self.chapter_page(&url, self.client.request_text(&url)?)?;
+            chapter.number = Some(i);
+        }
+        let url = chapter.url();
+        let img_re = Regex::new(IMG_TAG)?;
+        let mut largest: Option<(Vec<u8>, &str)> = None;
+        for tag in img_re.find_iter(&chapter.content()?) {
+            let Some(src) = image_src(url, tag.as_str()) else {
+                continue;
+            };
+            std::thread::sleep(self.pause());
+            // Only what is one of the known image formats by its bytes, not by what the site claims
+            match self.client.request_data(&src) {
+                Ok(data) => match image_type(&data) {
+                    Some(img_type) if largest.as_ref().is_none_or(|(img, _)| data.len() > img.len()) => {
+                        largest = Some((data, img_type));
+                    }
+                    Some(_) => {}
+                    None => Event::Warning { message: &format!("'{src}' is not an image") }.emit(),
+                },
+                Err(e) => Event::Warning { message: &format!("Skip the image '{src}': {e}") }.emit(),
+            }
+        }
+        let (img, img_type) = largest.ok_or(Box::new(NovelError::Other(format!("No image in chapter {n} ({url})"))))?;
+        let novel_dir = self.novel_dir()?;
+        std::fs::create_dir_all(&novel_dir)?;
+        let filepath = format!("{novel_dir}/{}.{img_type}", self.file_name(&self.cover.title()?));
+        Event::CoverSaved { path: &filepath }.emit();
+        self.save_file(&filepath, &self.optimize_image(&img))
+    }
+
+    // Downloads the images of the chapter at `chapter_url` and points them at the local copies, lazy loaded
+    // ones by their data-src. An image that can't be downloaded is dropped, a remote one would break
+    // offline. Each url is only fetched once per run, however many chapters show it
+    fn embed_images(&self, chapter_url: &str, xhtml: String) -> Result<String, Box<dyn std::error::Error>> {
+        let img_re = Regex::new(IMG_TAG)?;
+        let attr_re = Regex::new(r#"\s(?:data-src|src|srcset)="[^"]*""#)?;
+        for tag in img_re.find_iter(&xhtml) {
+            let Some(url) = image_src(chapter_url, tag.as_str()) else {
+                continue;
+            };
+            if self.images.borrow().contains_key(&url) {
+                continue;
+            }
+            std::thread::sleep(self.pause());
+            let path = match self.client.request_data(&url).and_then(|data| self.save_image(&data)) {
+                Ok(path) => Some(path),
+                Err(e) => {
+                    Event::Warning { message: &format!("Drop the image '{url}': {e}") }.emit();
+                    None
+                }
+            };
+            self.images.borrow_mut().insert(url, path);
+        }
+        let images = self.images.borrow();
+        Ok(img_re
+            .replace_all(&xhtml, |captures: &regex::Captures| {
+                let tag = &captures[0];
+                match image_src(chapter_url, tag).and_then(|url| images.get(&url).cloned()) {
+                    Some(Some(path)) => format!(r#"<img src="{path}"{}"#, &attr_re.replace_all(tag, "")["<img".len()..]),
+                    Some(None) => String::new(),
+                    None => tag.to_string(),
+                }
+            })
+            .to_string())
+    }
+
+    // Images are named after the SHA-256 of their bytes, the same name in every run, so one repeated
+    // across chapters is stored and embedded once. An image that is the cover points at the cover itself
+    fn save_image(&self, data: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+        let novel_dir = self.novel_dir()?;
+        let data = &*self.optimize_image(data);
+        if let Some(img_type) = self.saved_cover_type()? {
+            let cover = format!("{}.{img_type}", self.file_name(&self.cover.title()?));
+            if std::fs::read(format!("{novel_dir}/{cover}")).is_ok_and(|bytes| bytes == data) {
+                return Ok(cover);
+            }
+        }
+        let ext = image_type(data).ok_or(Box::new(NovelError::Image))?;
+        let name = format!("{IMAGES_DIR}/{}.{ext}", sha256_hex(data));
+        let filepath = format!("{novel_dir}/{name}");
+        if !std::path::Path::new(&filepath).exists() {
+            std::fs::create_dir_all(format!("{novel_dir}/{IMAGES_DIR}"))?;
+            self.save_file(&filepath, data)?;
+        }
+        Ok(name)
+    }
+
+    /// Copies pre-saved chapter pages from `dir` into the novel directory, in the order of
+    /// their leading numbers. The title of a page is its <title> or first <h1>, else the file name
+    pub fn import(&self, dir: &str, cover: Option<&str>) -> Result<u64, Box<dyn std::error::Error>> {
+        let novel_dir = self.novel_dir()?;
+        std::fs::create_dir_all(&novel_dir)?;
+        if let Some(cover) = cover {
+            let filepath = format!("{novel_dir}/{}.{}", self.file_name(&self.cover.title()?), self.cover.cover_img_type()?);
+            Event::CoverSaved { path: &filepath }.emit();
+            self.save_file(&filepath, &std::fs::read(cover)?)?;
+        }
+        let number_re = Regex::new(r#"^\d+"#)?;
+        let mut files = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+            if ["html", "htm", "xhtml"].contains(&ext.to_lowercase().as_str()) {
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                let number = number_re.find(&name).and_then(|m| m.as_str().parse::<u64>().ok());
+                files.push((number.unwrap_or(u64::MAX), name, path));
+            }
+        }
+        files.sort();
+
+        let title_selector = parse_selector("title, h1")?;
+        let body_selector = parse_selector("body")?;
+        for (i, (_, _, path)) in files.iter().enumerate() {
+            Event::Read { path: &path.display().to_string() }.emit();
+            let page = Html::parse_document(&std::fs::read_to_string(path)?);
+            // Made fit for a file name like the scraped ones, e.g. "Part 1/2"
+            let title = page
+                .select(&title_selector)
+                .map(|element| element.text().collect::<String>().trim().to_string())
+                .find(|title| !title.is_empty())
+                .unwrap_or_else(|| path.file_stem().unwrap_or_default().to_string_lossy().to_string());
+            let title = sanitize_filename(&title);
+            let content = match page.select(&body_selector).next() {
+                Some(body) => body.inner_html(),
+                None => page.root_element().inner_html(),
+            };
+            let number = i as u64 + 1;
+            let filepath = format!("{novel_dir}/{:0>8} {}.xhtml", number, self.file_name(&title));
+            Event::ChapterSaved { id: number, title: &title, path: &filepath }.emit();
+            self.save_file(&filepath, compose_chapter_xhtml(&title, content.trim())?.as_bytes())?;
+        }
+        Ok(files.len() as u64)
+    }
+
+    /// Saves the current chapter, returns the path of its file
+    pub fn chapter_save(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let novel_dir = self.novel_dir()?;
+        std::fs::create_dir_all(&novel_dir)?;
+        let chapter = self.chapter.as_ref().ok_or(Box::new(NovelError::Empty))?;
+        let title = chapter.title()?;
+        let mut content = replace_text(&chapter.content()?, &self.config.replacements)?;
+        let text = match self.config.format {
+            Format::Epub => {
+                if let Some(re) = &self.config.footnotes {
+                    content = extract_footnotes(&content, re);
+                }
+                self.embed_images(chapter.url(), compose_chapter_xhtml(&title, &content)?)?
+            }
+            Format::Md => compose_chapter_markdown(&title, &content),
+            Format::Txt => compose_chapter_text(&title, &content),
+        };
+
+        let number = self.chapter_number()?;
+        let filepath = self.chapter_path(&title, self.config.format.extension())?;
+        Event::ChapterSaved { id: number, title: &title, path: &filepath }.emit();
+        self.save_file(&filepath, text.as_bytes())?;
+        if self.config.keep_raw_html {
+            std::fs::create_dir_all(format!("{novel_dir}/raw"))?;
+            self.save_file(&format!("{novel_dir}/raw/{number}.html"), chapter.raw.as_bytes())?;
+        }
+        self.record_resume(chapter)?;
+        self.record_chapter(number, &title, &chapter.url)?;
+        Ok(filepath)
+    }
+
+    fn chapter_path(&self, title: &str, ext: &str) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(format!(
+            "{}/{:0>8} {}.{ext}",
+            self.novel_dir()?,
+            self.chapter_number()?,
+            self.file_name(title)
+        ))
+    }
+
+    /// Reads the saved chapter back and checks it parses as XML, see --verify-as-you-go
+    pub fn verify_saved(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.config.format != Format::Epub {
+            return Ok(());
+        }
+        let chapter = self.chapter.as_ref().ok_or(Box::new(NovelError::Empty))?;
+        let path = self.chapter_path(&chapter.title()?, "xhtml")?;
+        check_xml(&std::fs::read_to_string(&path)?)
+            .map_err(|e| Box::new(NovelError::Other(format!("'{path}' is not well-formed XML: {e}"))).into())
+    }
+
+    // Downloads the current chapter again, in place
+    pub fn refetch(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let url = self.chapter.as_ref().ok_or(Box::new(NovelError::Empty))?.url.clone();
+        std::thread::sleep(self.pause());
+        self.chapter = Some(self.chapter_page(&url, self.client.request_text_fresh(&url)?)?);
+        Ok(())
+    }
+
+    /// Whether the current chapter is saved already, by an earlier run.
+    /// A file cut short by an interrupted run doesn't count, the chapter is saved again
+    pub fn chapter_saved(&self) -> Result<bool, Box<dyn std::error::Error>> {
+        let chapter = self.chapter.as_ref().ok_or(Box::new(NovelError::Empty))?;
+        let path = self.chapter_path(&chapter.title()?, self.config.format.extension())?;
+        let Ok(saved) = std::fs::read_to_string(&path) else {
+            return Ok(false);
+        };
+        let complete = match self.config.format {
+            Format::Epub => saved.trim_end().ends_with("</html>"),
+            Format::Md | Format::Txt => !saved.trim().is_empty(),
+        };
+        if !complete {
+            Event::Warning { message: &format!("'{path}' is incomplete, saving it again") }.emit();
+        }
+        Ok(complete)
+    }
+}
+
+// The hashes of a run cut short by an error still get written
+impl Drop for Novel {
+    fn drop(&mut self) {
+        if let Err(e) = self.write_checksums() {
+            Event::Warning { message: &format!("{CHECKSUMS_FILE} not written: {e}") }.emit();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_filename_keeps_emoji_and_cjk() {
+        assert_eq!(sanitize_filename("Chapter 1 🐉 The Dragon"), "Chapter 1 🐉 The Dragon");
+        assert_eq!(sanitize_filename("第一章 開始"), "第一章 開始");
+    }
+
+    #[test]
+    fn sanitize_filename_truncates_long_titles_on_char_boundaries() {
+        // 3 bytes each, 66 of them fit in the cap
+        let cjk = sanitize_filename(&"開".repeat(100));
+        assert_eq!(cjk, "開".repeat(66));
+        // 4 bytes each, exactly 50 fit
+        let emoji = sanitize_filename(&"🐉".repeat(60));
+        assert_eq!(emoji, "🐉".repeat(50));
+    }
+
+    #[test]
+    fn encode_filename_percent_encodes_whole_chars() {
+        assert_eq!(encode_filename("第一章 🐉"), "%E7%AC%AC%E4%B8%80%E7%AB%A0 %F0%9F%90%89");
+        assert_eq!(encode_filename("100%"), "100%25");
+    }
+
+    #[test]
+    fn encode_filename_caps_the_encoded_length() {
+        // 9 encoded bytes per char, 22 of them fit
+        let encoded = encode_filename(&"開".repeat(100));
+        assert_eq!(encoded.len(), 22 * 9);
+        let decoded = percent_encoding::percent_decode_str(&encoded).decode_utf8().unwrap();
+        assert_eq!(decoded, "開".repeat(22));
+    }
+
+    #[test]
+    fn sanitize_filename_removes_reserved_characters() {
+        assert_eq!(sanitize_filename(r#"a<b>c:d"e/f\g|h?i*j"#), "abcdefghij");
+        assert_eq!(sanitize_filename("  Chapter \n\t 1  "), "Chapter 1");
+        assert_eq!(sanitize_filename("To be continued... "), "To be continued");
+        assert_eq!(sanitize_filename("CON"), "CON_");
+        assert_eq!(sanitize_filename("nul.txt"), "nul.txt_");
+        assert_eq!(sanitize_filename("???"), "untitled");
+    }
+
+    #[test]
+    fn sanitize_filename_cuts_before_a_char_crossing_the_cap() {
+        // 1 + 50 * 4 bytes, the last dragon would end past 200
+        let name = sanitize_filename(&format!("a{}", "🐉".repeat(50)));
+        assert_eq!(name, format!("a{}", "🐉".repeat(49)));
+        let name = sanitize_filename(&format!("第{}", "一章 開始 ".repeat(40)));
+        assert!(name.len() <= MAX_FILENAME_BYTES && !name.ends_with(' '));
+        assert!(name.starts_with("第一章 開始 一章"));
+    }
+
+    const CHAPTER: &str = "https://www.lightnovelworld.com/novel/test/chapter-1";
+
+    #[test]
+    fn resolve_url_handles_each_href_shape() {
+        let next = "https://www.lightnovelworld.com/novel/test/chapter-2";
+        assert_eq!(resolve_url(CHAPTER, "https://www.lightnovelworld.com/novel/test/chapter-2").unwrap(), next);
+        assert_eq!(resolve_url(CHAPTER, "//www.lightnovelworld.com/novel/test/chapter-2").unwrap(), next);
+        assert_eq!(resolve_url(CHAPTER, "/novel/test/chapter-2").unwrap(), next);
+        assert_eq!(resolve_url(CHAPTER, "chapter-2").unwrap(), next);
+        assert_eq!(resolve_url(CHAPTER, " chapter-2#comments ").unwrap(), next);
+        let other = "https://www.lightnovelworld.com/novel/other/chapter-1";
+        assert_eq!(resolve_url(CHAPTER, "../other/chapter-1").unwrap(), other);
+    }
+
+    #[test]
+    fn resolve_url_keeps_the_page_for_a_bare_fragment() {
+        assert_eq!(resolve_url(CHAPTER, "#top").unwrap(), CHAPTER);
+    }
+
+    #[test]
+    fn resolve_url_refuses_other_schemes() {
+        assert!(resolve_url(CHAPTER, "javascript:void(0)").is_err());
+        assert!(resolve_url(CHAPTER, "ftp://www.lightnovelworld.com/chapter-2").is_err());
+        assert!(resolve_url("not a url", "chapter-2").is_err());
+    }
+
+    #[test]
+    fn image_src_prefers_data_src_and_resolves_it() {
+        let base = "https://www.lightnovelworld.com/novel/n/chapter-1";
+        let lazy = r#"<img src="data:image/gif;base64,R0lGOD" data-src="../images/a.png?x=1&amp;y=2">"#;
+        assert_eq!(image_src(base, lazy).as_deref(), Some("https://www.lightnovelworld.com/novel/images/a.png?x=1&y=2"));
+        assert_eq!(image_src(base, r#"<img alt="a" src="//cdn.example.com/a.jpg">"#).as_deref(), Some("https://cdn.example.com/a.jpg"));
+        assert_eq!(image_src(base, r#"<img src="data:image/gif;base64,R0lGOD">"#), None);
+        assert_eq!(image_src(base, "<img>"), None);
+    }
+
+    #[test]
+    fn shift_url_number_keeps_zero_padding() {
+        assert_eq!(shift_url_number("https://a.b/c/chapter-007", -1).as_deref(), Some("https://a.b/c/chapter-006"));
+        assert_eq!(shift_url_number("https://a.b/c/chapter-009.html", 3).as_deref(), Some("https://a.b/c/chapter-012.html"));
+        assert_eq!(shift_url_number("https://a.b/c/chapter-10", -1).as_deref(), Some("https://a.b/c/chapter-9"));
+        assert_eq!(shift_url_number("https://a.b/novel/prologue", 1), None);
+    }
+
+    #[test]
+    fn sha256_hex_matches_the_fips_180_vectors() {
+        assert_eq!(sha256_hex(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(sha256_hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        // 56 bytes, the padding takes a second block
+        assert_eq!(
+            sha256_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+}
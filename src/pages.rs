@@ -0,0 +1,1076 @@
+//! The cover and chapter pages of the supported sites, and what is made of their content
+
+use crate::{
+    epub::{xml_escape, STYLESHEET},
+    error::NovelError,
+    event::Event,
+    novel::{canonicalize_url, resolve_url, sanitize_filename},
+};
+use chrono::{Duration as DateDuration, NaiveDate, NaiveDateTime, Utc};
+use html_builder::{Buffer, Html5};
+use regex::Regex;
+use scraper::{ElementRef, Html, Node, Selector};
+use std::{borrow::Cow, cell::OnceCell, collections::HashSet, fmt::Write};
+
+pub(crate) fn parse_selector(path: &str) -> Result<Selector, NovelError> {
+    Selector::parse(path).map_err(|_| NovelError::SelectorParse(path.to_string()))
+}
+
+// The first element matching the selector, the matches are logged at the debug level
+fn select_first<'a>(page: &'a Html, selector_path: &str) -> Result<ElementRef<'a>, NovelError> {
+    let selector = parse_selector(selector_path)?;
+    let element = page
+        .select(&selector)
+        .next()
+        .ok_or(NovelError::Selector { path: selector_path.to_string(), page: None })?;
+    Event::Debug { message: &format!("'{selector_path}' matched <{}>", element.value().name()) }.emit();
+    Ok(element)
+}
+
+// Enough of the page to tell a changed markup or an error page from the expected one
+const SNIPPET_BYTES: usize = 200;
+
+// Names the page a selector didn't match on, with the start of its body
+fn on_page(error: NovelError, page: &Html, name: String) -> NovelError {
+    let NovelError::Selector { path, page: None } = error else {
+        return error;
+    };
+    let body = parse_selector("body").ok().and_then(|body| page.select(&body).next());
+    let html = body.map_or_else(|| page.html(), |body| body.inner_html());
+    let mut snippet = html.split_whitespace().collect::<Vec<_>>().join(" ");
+    if snippet.len() > SNIPPET_BYTES {
+        let end = (0..=SNIPPET_BYTES).rev().find(|i| snippet.is_char_boundary(*i)).unwrap_or(0);
+        snippet.truncate(end);
+        snippet.push_str("...");
+    }
+    NovelError::Selector { path, page: Some(format!("{name}, which starts with '{snippet}'")) }
+}
+
+/// Where a site keeps the parts of its cover and chapter pages, one implementation per
+/// supported site, see `site_adapter`
+pub trait SiteAdapter: Sync {
+    fn title_selector(&self) -> &str;
+    fn author_selector(&self) -> &str;
+    // The cover image, its url is in the `cover_attr` attribute
+    fn cover_selector(&self) -> &str;
+    fn cover_attr(&self) -> &str {
+        "src"
+    }
+    // Links to the first chapter on the cover page, tried in order
+    fn first_chapter_selectors(&self) -> &[&str];
+    fn chapter_title_selector(&self) -> &str;
+    // Tried in order, the first one with any text is the content
+    fn content_selectors(&self) -> &[&str];
+    fn next_chapter_selector(&self) -> &str;
+    fn prev_chapter_selector(&self) -> &str;
+    // The first page of the full chapter list of the novel at `novel_url` and the selector of
+    // its chapter links, oldest first. Where the first chapter is looked up when the cover's
+    // link leads elsewhere
+    fn chapter_list(&self, _novel_url: &str) -> Option<(String, &str)> {
+        None
+    }
+    // The optional details of the cover page, each tried in order. None by default, a site
+    // without them still gets its chapters saved
+    fn description_selectors(&self) -> &[&str] {
+        &[]
+    }
+    // The original or romanized title under the main one
+    fn alt_title_selectors(&self) -> &[&str] {
+        &[]
+    }
+    fn translator_selectors(&self) -> &[&str] {
+        &[]
+    }
+    // The elements of which one reads "Completed" or "Ongoing"
+    fn status_selectors(&self) -> &[&str] {
+        &[]
+    }
+    // The date of the latest update, in its `datetime` attribute or the text
+    fn updated_date_selectors(&self) -> &[&str] {
+        &[]
+    }
+    // The elements of which one reads "123 Chapters" or "Chapters: 123"
+    fn chapter_count_selectors(&self) -> &[&str] {
+        &[]
+    }
+}
+
+pub struct LightNovelWorldAdapter;
+
+impl SiteAdapter for LightNovelWorldAdapter {
+    fn title_selector(&self) -> &str {
+        // #novel > header > div.header-body.container > div.novel-info > div.main-head > h1
+        "h1.novel-title"
+    }
+
+    fn author_selector(&self) -> &str {
+        // #novel > header > div.header-body.container > div.novel-info > div.main-head > div.author > a > span
+        "div.author > a > span"
+    }
+
+    fn cover_selector(&self) -> &str {
+        // #novel > header > div.header-body.container > div.fixed-img > figure > img
+        "div.fixed-img > figure > img"
+    }
+
+    fn cover_attr(&self) -> &str {
+        "data-src"
+    }
+
+    fn first_chapter_selectors(&self) -> &[&str] {
+        // Some cover pages have no read button, only the chapter list
+        &["#readchapterbtn", "ul.chapter-list li a"]
+    }
+
+    fn chapter_title_selector(&self) -> &str {
+        "span.chapter-title"
+    }
+
+    fn content_selectors(&self) -> &[&str] {
+        &CONTENT_SELECTORS
+    }
+
+    fn next_chapter_selector(&self) -> &str {
+        // #chapter-article > section > div.chapternav.skiptranslate > a.button.nextchap
+        "a.button.nextchap"
+    }
+
+    fn prev_chapter_selector(&self) -> &str {
+        "a.button.prevchap"
+    }
+
+    fn chapter_list(&self, novel_url: &str) -> Option<(String, &str)> {
+        let mut url = url::Url::parse(novel_url).ok()?;
+        url.set_query(None);
+        url.set_fragment(None);
+        Some((format!("{}/chapters", url.as_str().trim_end_matches('/')), "ul.chapter-list li a"))
+    }
+
+    fn description_selectors(&self) -> &[&str] {
+        // #info > div.summary > div.content, the outer ones hold the inner ones
+        &["div.summary .content", "div.summary", ".description"]
+    }
+
+    fn alt_title_selectors(&self) -> &[&str] {
+        &["h2.alternative-title, div.main-head h2"]
+    }
+
+    fn translator_selectors(&self) -> &[&str] {
+        // Only some covers credit a translator, e.g. <div class="translator">Translator: <a>Name</a></div>
+        &["div.translator a, div.translator span"]
+    }
+
+    fn status_selectors(&self) -> &[&str] {
+        &["div.header-stats > span"]
+    }
+
+    fn updated_date_selectors(&self) -> &[&str] {
+        // #novel > header > div.header-body.container > div.novel-info > div.updinfo > strong > time
+        &["div.updinfo time, div.updinfo strong"]
+    }
+
+    fn chapter_count_selectors(&self) -> &[&str] {
+        // #novel > header > div.header-body.container > div.novel-info > div.header-stats > span > strong
+        &["div.header-stats > span"]
+    }
+}
+
+// The domains of the supported sites, their subdomains included
+pub static SITES: [(&str, &dyn SiteAdapter); 1] = [("lightnovelworld.com", &LightNovelWorldAdapter)];
+
+/// The adapter for the site at `host`, a url as returned by `Novel::host`
+pub fn site_adapter(host: &str) -> Option<&'static dyn SiteAdapter> {
+    let parsed = url::Url::parse(host).ok()?;
+    let host = parsed.host_str()?;
+    SITES
+        .iter()
+        .find(|(domain, _)| host == *domain || host.ends_with(&format!(".{domain}")))
+        .map(|(_, adapter)| *adapter)
+}
+
+pub struct CoverPage {
+    url: String,
+    page: Html,
+    pub(crate) site: &'static dyn SiteAdapter,
+    // Given for books assembled from local files, there is no page to read them from
+    metadata: Option<CoverMetadata>,
+}
+
+struct CoverMetadata {
+    title: String,
+    author: String,
+    cover_img_type: Option<String>,
+}
+
+impl CoverPage {
+    pub fn new(url: &str, page: Html, site: &'static dyn SiteAdapter) -> Self {
+        Self { url: url.to_string(), page, site, metadata: None }
+    }
+
+    fn select(&self, selector_path: &str) -> Result<ElementRef<'_>, NovelError> {
+        select_first(&self.page, selector_path).map_err(|e| self.missing(e))
+    }
+
+    // The elements matched by the first of `selector_paths` that matches any, in document order
+    fn select_any(&self, selector_paths: &[&str]) -> Vec<ElementRef<'_>> {
+        selector_paths
+            .iter()
+            .filter_map(|selector_path| parse_selector(selector_path).ok())
+            .map(|selector| self.page.select(&selector).collect::<Vec<_>>())
+            .find(|elements| !elements.is_empty())
+            .unwrap_or_default()
+    }
+
+    fn missing(&self, error: NovelError) -> NovelError {
+        let name = match self.url.is_empty() {
+            true => "the cover page".to_string(),
+            false => format!("the cover page '{}'", self.url),
+        };
+        on_page(error, &self.page, name)
+    }
+
+    // A stand-in for books assembled from local files, everything but the given metadata is missing.
+    // Escaped like the inner html the accessors return for a scraped page
+    pub fn from_metadata(title: &str, author: &str, cover_img_type: Option<&str>) -> Self {
+        let metadata = CoverMetadata {
+            title: title.to_string(),
+            author: author.to_string(),
+            cover_img_type: cover_img_type.map(str::to_string),
+        };
+        Self { url: String::new(), page: Html::new_document(), site: &LightNovelWorldAdapter, metadata: Some(metadata) }
+    }
+
+    pub fn title(&self) -> Result<String, Box<dyn std::error::Error>> {
+        if let Some(metadata) = &self.metadata {
+            return Ok(sanitize_filename(&metadata.title));
+        }
+        let selector_path = self.site.title_selector();
+        let title = self.select(selector_path)?.text().collect::<String>();
+        Ok(sanitize_filename(&title))
+    }
+
+    pub fn author(&self) -> Result<String, Box<dyn std::error::Error>> {
+        if let Some(metadata) = &self.metadata {
+            return Ok(metadata.author.clone());
+        }
+        let selector_path = self.site.author_selector();
+        let author = self.select(selector_path)?.text().collect::<String>().trim().to_string();
+        Ok(author)
+    }
+
+    // The original or romanized title under the main one, if the cover shows a different one
+    pub fn alt_title(&self) -> Option<String> {
+        let alt_title = self.select_any(self.site.alt_title_selectors()).first()?.text().collect::<String>().trim().to_string();
+        let title = self.title().ok()?;
+        (!alt_title.is_empty() && alt_title != title).then_some(alt_title)
+    }
+
+    /// The synopsis, one line per paragraph, without the ads nested in it
+    pub fn description(&self) -> Option<String> {
+        let element = *self.select_any(self.site.description_selectors()).first()?;
+        let fragment = Html::parse_fragment(&remove_ads(&element.inner_html()));
+        let paragraph = parse_selector("p").ok()?;
+        let mut paragraphs: Vec<String> = fragment
+            .select(&paragraph)
+            .map(|p| p.text().collect::<Vec<_>>().join(" ").split_whitespace().collect::<Vec<_>>().join(" "))
+            .filter(|p| !p.is_empty())
+            .collect();
+        if paragraphs.is_empty() {
+            let text = fragment.root_element().text().collect::<Vec<_>>().join(" ");
+            paragraphs.push(text.split_whitespace().collect::<Vec<_>>().join(" "));
+        }
+        let description = paragraphs.join("\n");
+        (!description.trim().is_empty()).then_some(description)
+    }
+
+    // Only some covers credit a translator
+    pub fn translator(&self) -> Option<String> {
+        let name = self.select_any(self.site.translator_selectors()).first()?.text().collect::<String>().trim().to_string();
+        (!name.is_empty()).then_some(name)
+    }
+
+    /// "Completed" or "Ongoing" as shown among the header stats
+    pub fn status(&self) -> Option<String> {
+        let re = Regex::new(r#"(?i)\b(completed|ongoing)\b"#).ok()?;
+        self.select_any(self.site.status_selectors()).into_iter().find_map(|span| {
+            let text = span.text().collect::<Vec<_>>().join(" ");
+            let status = re.captures(&text)?[1].to_lowercase();
+            Some(if status == "completed" { "Completed" } else { "Ongoing" }.to_string())
+        })
+    }
+
+    pub fn cover_img_url(&self) -> Result<String, Box<dyn std::error::Error>> {
+        if let Some(metadata) = &self.metadata {
+            let img_type = metadata.cover_img_type.as_ref().ok_or(Box::new(NovelError::Image))?;
+            return Ok(format!("cover.{img_type}"));
+        }
+        let selector_path = self.site.cover_selector();
+        let attr_name = self.site.cover_attr();
+        let cover_url = self.select(selector_path)?
+            .value()
+            .attr(attr_name)
+            .ok_or(Box::new(NovelError::Attr(attr_name.to_string())))?
+            .to_string();
+        Ok(cover_url)
+    }
+
+    pub fn cover_img_type(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let img_url = self.cover_img_url()?;
+        let re = Regex::new(r#"([[:alpha:]]+)(?:\?v=\d*)??$"#)?;
+        // let re = Regex::new(r#"([[:alpha:]]+)$"#)?;
+        let ext = re
+            .captures(&img_url)
+            .ok_or(Box::new(NovelError::Image))?
+            .get(1)
+            .unwrap()
+            .as_str()
+            .to_string();
+        Ok(ext)
+    }
+
+    pub fn updated_date(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let selector_paths = self.site.updated_date_selectors();
+        let attr_name = "datetime";
+        let Some(&element) = self.select_any(selector_paths).first() else {
+            let path = selector_paths.join(", ");
+            return Err(Box::new(self.missing(NovelError::Selector { path, page: None })));
+        };
+        let updated = match element.value().attr(attr_name) {
+            Some(datetime) => datetime.to_string(),
+            None => element.text().collect::<String>(),
+        };
+        let date = parse_date(updated.trim(), Utc::now().date_naive())
+            .ok_or(Box::new(NovelError::Other(format!("Unrecognized date '{}'", updated.trim()))))?;
+        Ok(date.format("%Y-%m-%d").to_string())
+    }
+
+    pub fn chapter_count(&self) -> Option<u64> {
+        let re = Regex::new(r#"(?i)([\d,]+)\s*chapters|chapters:?\s*([\d,]+)"#).unwrap();
+        self.select_any(self.site.chapter_count_selectors()).into_iter().find_map(|span| {
+            let text = span.text().collect::<Vec<_>>().join(" ");
+            let captures = re.captures(&text)?;
+            let count = captures.get(1).or(captures.get(2))?.as_str().replace(',', "");
+            count.parse().ok()
+        })
+    }
+
+    // The error is the one of the first selector, the others are fallbacks
+    pub fn chapter_first_url(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let mut error = None;
+        for selector_path in self.site.first_chapter_selectors() {
+            match self.link(selector_path) {
+                Ok(url) => return Ok(url),
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        Err(error.unwrap_or(Box::new(self.missing(NovelError::Selector { path: "first chapter".to_string(), page: None }))))
+    }
+
+    fn link(&self, selector_path: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let attr_name = "href";
+
+        let chapter_url = self.select(selector_path)?
+            .value()
+            .attr(attr_name)
+            .ok_or(Box::new(NovelError::Attr(attr_name.to_string())))?
+            .to_string();
+        Ok(chapter_url)
+    }
+}
+
+/// Parses both absolute ("2023-03-14 10:05", "Mar 14, 2023") and relative ("3 days ago") dates.
+fn parse_date(text: &str, today: NaiveDate) -> Option<NaiveDate> {
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M:%S%.fZ"] {
+        if let Ok(datetime) = NaiveDateTime::parse_from_str(text, format) {
+            return Some(datetime.date());
+        }
+    }
+    for format in ["%Y-%m-%d", "%b %d, %Y", "%B %d, %Y", "%d %b %Y", "%d %B %Y"] {
+        if let Ok(date) = NaiveDate::parse_from_str(text, format) {
+            return Some(date);
+        }
+    }
+    let re = Regex::new(r#"(?i)(\d+|an?)\s+(minute|hour|day|week|month|year)s?\s+ago"#).ok()?;
+    let captures = re.captures(text)?;
+    let count: i64 = captures[1].parse().unwrap_or(1);
+    let days = match captures[2].to_lowercase().as_str() {
+        "minute" | "hour" => 0,
+        "day" => count,
+        "week" => count * 7,
+        "month" => count * 30,
+        _ => count * 365,
+    };
+    today.checked_sub_signed(DateDuration::days(days))
+}
+
+// Tried in order, older chapters of long running novels may still use a previous layout,
+// `article` last as it may wrap the navigation as well
+const CONTENT_SELECTORS: [&str; 5] =
+    ["div.chapter-content", "#chapter-container", "div.chapter-body", "section.chapter-content", "article"];
+
+// Shown instead of the text of chapters yet to be released, lowercase
+const PLACEHOLDER_MARKERS: [&str; 6] = [
+    "coming soon",
+    "not yet released",
+    "not available yet",
+    "stay tuned",
+    "will be released",
+    "to be continued",
+];
+
+// Longer texts are real chapters even if they mention a marker, e.g. "to be continued" at the end
+const PLACEHOLDER_MAX_CHARS: usize = 300;
+
+// A page without any content is an error of its own rather than a placeholder
+pub(crate) fn is_placeholder(chapter: &ChapterPage) -> Result<bool, Box<dyn std::error::Error>> {
+    let text = chapter.text()?.to_lowercase();
+    Ok(text.is_empty()
+        || (text.chars().count() <= PLACEHOLDER_MAX_CHARS && PLACEHOLDER_MARKERS.iter().any(|marker| text.contains(marker))))
+}
+
+// Words of the ids and classes of ads, split on '-' and '_' so "ad-slot" matches and "header" does not
+const AD_WORDS: [&str; 7] = ["ad", "ads", "adsbox", "adsbygoogle", "advert", "advertisement", "sponsored"];
+
+// Elements never part of the text
+const AD_ELEMENTS: [&str; 5] = ["script", "style", "iframe", "ins", "noscript"];
+
+/// Removes the ads from an html fragment: the elements named like one and the scripts and frames
+/// they come with, and the divs left with nothing to show, neither text nor a rule or an image.
+/// The other divs and their content stay
+fn remove_ads(html: &str) -> String {
+    fn is_ad(element: ElementRef) -> bool {
+        let value = element.value();
+        AD_ELEMENTS.contains(&value.name())
+            || value
+                .id()
+                .into_iter()
+                .chain(value.classes())
+                .flat_map(|name| name.split(['-', '_']))
+                .any(|word| AD_WORDS.contains(&word.to_ascii_lowercase().as_str()))
+    }
+    fn is_empty_div(element: ElementRef) -> bool {
+        element.value().name() == "div"
+            && element.text().all(|text| text.trim().is_empty())
+            && !element
+                .descendants()
+                .any(|node| matches!(node.value(), Node::Element(child) if matches!(child.name(), "img" | "hr")))
+    }
+    let mut fragment = Html::parse_fragment(html);
+    let ads: Vec<_> = fragment
+        .root_element()
+        .descendants()
+        .filter(|node| ElementRef::wrap(*node).is_some_and(|element| is_ad(element) || is_empty_div(element)))
+        .map(|node| node.id())
+        .collect();
+    for id in ads {
+        if let Some(mut node) = fragment.tree.get_mut(id) {
+            node.detach();
+        }
+    }
+    fragment.root_element().inner_html()
+}
+
+// Inline translator notes like "[TL: ...]" or "(T/N: ...)", the first group is the note
+pub const FOOTNOTES: &str = r#"[\[(]\s*(?i:TL|T/N|TN|Translator'?s? note)\s*:\s*([^\])]+?)\s*[\])]"#;
+
+/// Moves the notes matched by `re` to a numbered list at the end of the chapter,
+/// leaving a linked marker in their place
+pub(crate) fn extract_footnotes(content: &str, re: &Regex) -> String {
+    let mut notes = vec![];
+    let content = re.replace_all(content, |captures: &regex::Captures| {
+        notes.push(captures.get(1).unwrap_or_else(|| captures.get(0).unwrap()).as_str().to_string());
+        let n = notes.len();
+        format!(r##"<sup><a id="noteref-{n}" href="#note-{n}">{n}</a></sup>"##)
+    });
+    if notes.is_empty() {
+        return content.to_string();
+    }
+    let mut endnotes = String::from(r#"<ol class="endnotes">"#);
+    for (i, note) in notes.iter().enumerate() {
+        let n = i + 1;
+        endnotes.push_str(&format!(r##"<li id="note-{n}">{note} <a href="#noteref-{n}">&#8617;</a></li>"##));
+    }
+    endnotes.push_str("</ol>");
+    format!(r#"{content}<hr class="scene-break" />{endnotes}"#)
+}
+
+/// The number the site gives a chapter in its title, e.g. 42 in "Chapter 42: The Return"
+pub fn title_chapter_number(title: &str) -> Option<u64> {
+    let re = Regex::new(r#"(?i)\b(?:chapter|ch\.?)\s*(\d+)"#).ok()?;
+    re.captures(title)?[1].parse().ok()
+}
+
+/// The volume a chapter title starts with, e.g. "Volume 2" in "Vol. 2 Chapter 5: The Return",
+/// books and arcs alike. Only at the start, where it can't be part of the chapter's own name
+pub fn title_volume(title: &str) -> Option<String> {
+    let re = Regex::new(r#"^\W*((?i:vol(?:ume)?\b\.?|book\b|arc\b))\s*(\d+|[IVXLC]+)\b"#).ok()?;
+    let captures = re.captures(title)?;
+    let kind = match captures[1].to_lowercase().as_str() {
+        "book" => "Book",
+        "arc" => "Arc",
+        _ => "Volume",
+    };
+    Some(format!("{kind} {}", &captures[2]))
+}
+
+/// Applies the rules in order, each to the result of the previous one, only to the text between tags
+pub(crate) fn replace_text(html: &str, rules: &[(Regex, String)]) -> Result<String, Box<dyn std::error::Error>> {
+    let tag = Regex::new(r#"<[^>]*>"#)?;
+    let replace = |text: &str| {
+        rules
+            .iter()
+            .fold(text.to_string(), |text, (re, replacement)| re.replace_all(&text, replacement.as_str()).to_string())
+    };
+    let mut replaced = String::with_capacity(html.len());
+    let mut last = 0;
+    for element in tag.find_iter(html) {
+        replaced.push_str(&replace(&html[last..element.start()]));
+        replaced.push_str(element.as_str());
+        last = element.end();
+    }
+    replaced.push_str(&replace(&html[last..]));
+    Ok(replaced)
+}
+
+// The text in the content comes escaped, a literal rule has to match and produce it that way
+pub fn parse_replacement(rule: &str, regex: bool) -> Result<(Regex, String), Box<dyn std::error::Error>> {
+    let (old, new) = rule
+        .split_once("=>")
+        .filter(|(old, _)| !old.is_empty())
+        .ok_or(Box::new(NovelError::Other(format!("Malformed replacement '{rule}', expected 'old=>new'"))))?;
+    if regex {
+        return Ok((Regex::new(old)?, new.to_string()));
+    }
+    let escape = |text: &str| text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    Ok((Regex::new(&regex::escape(&escape(old)))?, escape(new).replace('$', "$$")))
+}
+
+/// Converts chapter html into Markdown, keeping the paragraphs, line breaks, headings and emphasis
+fn html_to_markdown(html: &str) -> String {
+    flatten_html(html, true)
+}
+
+// The same without any markup, headings and scene breaks only stand apart as paragraphs
+fn html_to_text(html: &str) -> String {
+    flatten_html(html, false)
+}
+
+fn flatten_html(html: &str, markdown: bool) -> String {
+    let fragment = Html::parse_fragment(html);
+    let mut flat = String::new();
+    markdown_children(fragment.root_element(), markdown, &mut flat);
+    let mut result = String::new();
+    let mut blank = true;
+    for line in flat.lines().map(str::trim_start) {
+        if line.trim().is_empty() {
+            if !blank {
+                result.push('\n');
+            }
+            blank = true;
+        } else {
+            result.push_str(line);
+            result.push('\n');
+            blank = false;
+        }
+    }
+    result.trim_end().to_string()
+}
+
+fn markdown_children(element: ElementRef, markdown: bool, out: &mut String) {
+    for child in element.children() {
+        match child.value() {
+            Node::Text(text) => {
+                // Whitespace collapses to a single space, also between inline elements
+                let words = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                if text.starts_with(char::is_whitespace) && !out.ends_with(char::is_whitespace) {
+                    out.push(' ');
+                }
+                out.push_str(&words);
+                if !words.is_empty() && text.ends_with(char::is_whitespace) {
+                    out.push(' ');
+                }
+            }
+            Node::Element(_) => {
+                if let Some(element) = ElementRef::wrap(child) {
+                    markdown_element(element, markdown, out);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn markdown_element(element: ElementRef, markdown: bool, out: &mut String) {
+    match element.value().name() {
+        "p" | "div" => {
+            out.push_str("\n\n");
+            markdown_children(element, markdown, out);
+            out.push_str("\n\n");
+        }
+        "br" if markdown => out.push_str("  \n"),
+        "br" => out.push('\n'),
+        "hr" if markdown => out.push_str("\n\n---\n\n"),
+        "hr" => out.push_str("\n\n* * *\n\n"),
+        name @ ("em" | "i" | "strong" | "b") if markdown => {
+            let marker = if matches!(name, "em" | "i") { "*" } else { "**" };
+            let mut inner = String::new();
+            markdown_children(element, markdown, &mut inner);
+            // The markers hug the words, the spaces around them stay outside
+            if inner.starts_with(char::is_whitespace) && !out.ends_with(char::is_whitespace) {
+                out.push(' ');
+            }
+            if !inner.trim().is_empty() {
+                out.push_str(&format!("{marker}{}{marker}", inner.trim()));
+            }
+            if inner.ends_with(char::is_whitespace) && !inner.trim().is_empty() {
+                out.push(' ');
+            }
+        }
+        name @ ("h1" | "h2" | "h3" | "h4" | "h5" | "h6") => {
+            out.push_str("\n\n");
+            if markdown {
+                let level = name[1..].parse().unwrap_or(1);
+                out.push_str(&format!("{} ", "#".repeat(level)));
+            }
+            markdown_children(element, markdown, out);
+            out.push_str("\n\n");
+        }
+        "script" | "style" => {}
+        _ => markdown_children(element, markdown, out),
+    }
+}
+
+// The title is text in both, the entities it comes with as inner HTML are decoded
+pub(crate) fn compose_chapter_markdown(title: &str, content: &str) -> String {
+    format!("## {title}\n\n{}\n", html_to_markdown(content))
+}
+
+pub(crate) fn compose_chapter_text(title: &str, content: &str) -> String {
+    format!("{title}\n\n{}\n", html_to_text(content))
+}
+
+/// Turns the HTML serialization of the content into XML: void elements get closed
+/// and "&nbsp;", unknown to XML without a DTD, becomes a character reference
+fn xhtml_fragment(html: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let void = Regex::new(r#"<(area|br|col|embed|hr|img|input|source|track|wbr)\b([^>]*?)\s*/?>"#)?;
+    Ok(void.replace_all(html, "<$1$2 />").replace("&nbsp;", "&#160;"))
+}
+
+/// Checks that `text` is well-formed XML: tags balanced and properly nested, attributes quoted
+/// and not repeated, and only the predefined or numeric entities used
+pub(crate) fn check_xml(text: &str) -> Result<(), String> {
+    let tag = Regex::new(r#"^<([A-Za-z_:][\w:.-]*)((?:\s+[A-Za-z_:][\w:.-]*\s*=\s*(?:"[^"<]*"|'[^'<]*'))*)\s*(/?)>"#).unwrap();
+    let close = Regex::new(r#"^</([A-Za-z_:][\w:.-]*)\s*>"#).unwrap();
+    let attr = Regex::new(r#"([A-Za-z_:][\w:.-]*)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap();
+    let entity = Regex::new(r#"^&(?:amp|lt|gt|quot|apos|#[0-9]+|#x[0-9a-fA-F]+);"#).unwrap();
+    let line = |pos: usize| text[..pos].matches('\n').count() + 1;
+    let check_entities = |chunk: &str, offset: usize| {
+        for (i, _) in chunk.match_indices('&') {
+            if !entity.is_match(&chunk[i..]) {
+                return Err(format!("undefined entity at line {}", line(offset + i)));
+            }
+        }
+        Ok(())
+    };
+    let mut open: Vec<&str> = vec![];
+    let mut pos = 0;
+    while let Some(start) = text[pos..].find('<').map(|i| pos + i) {
+        check_entities(&text[pos..start], pos)?;
+        let rest = &text[start..];
+        let skipped = [("<!--", "-->"), ("<![CDATA[", "]]>"), ("<?", "?>"), ("<!", ">")]
+            .iter()
+            .find(|(opening, _)| rest.starts_with(opening))
+            .map(|(_, closing)| rest.find(closing).map(|end| end + closing.len()));
+        if let Some(end) = skipped {
+            pos = start + end.ok_or(format!("unterminated markup at line {}", line(start)))?;
+            continue;
+        }
+        if let Some(captures) = close.captures(rest) {
+            let name = captures.get(1).unwrap().as_str();
+            match open.pop() {
+                Some(opened) if opened == name => {}
+                Some(opened) => return Err(format!("'</{name}>' closes '<{opened}>' at line {}", line(start))),
+                None => return Err(format!("'</{name}>' closes nothing at line {}", line(start))),
+            }
+            pos = start + captures[0].len();
+            continue;
+        }
+        let captures = tag.captures(rest).ok_or(format!("malformed tag at line {}", line(start)))?;
+        let mut names = HashSet::new();
+        for attribute in attr.captures_iter(&captures[2]) {
+            if !names.insert(attribute.get(1).unwrap().as_str()) {
+                return Err(format!("attribute '{}' repeated at line {}", &attribute[1], line(start)));
+            }
+            let value = attribute.get(2).or(attribute.get(3)).unwrap();
+            check_entities(value.as_str(), start)?;
+        }
+        if captures[3].is_empty() {
+            open.push(captures.get(1).unwrap().as_str());
+        }
+        pos = start + captures[0].len();
+    }
+    check_entities(&text[pos..], pos)?;
+    match open.last() {
+        Some(name) => Err(format!("'<{name}>' is never closed")),
+        None => Ok(()),
+    }
+}
+
+pub(crate) fn compose_chapter_xhtml(title: &str, content: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut buf = Buffer::new();
+    buf.void_child(Cow::Borrowed("?xml version='1.0' encoding='utf-8'?"));
+    buf.doctype();
+    let mut html = buf
+        .html()
+        .attr(r#"xmlns="http://www.w3.org/1999/xhtml""#)
+        .attr(r#"xml:lang="en-US""#);
+    let mut head = html.head().raw();
+    head.write_str(
+        r#"<meta http-equiv="Content-Type" content="text/html; charset=utf-8" />"#,
+    )?;
+    // The title is plain text, escaped here as in the heading
+    let title = xml_escape(title);
+    write!(head, "<title>{title}</title>")?;
+    // Chapters sit next to the stylesheet in the EPUB, see `build_epub`
+    write!(
+        head,
+        r#"<link rel="stylesheet" type="text/css" href="{STYLESHEET}" />"#
+    )?;
+
+    let mut body = html.body().raw();
+    writeln!(body, "<h1>{title}</h1>")?;
+    writeln!(body, "{}", xhtml_fragment(content)?)?;
+    Ok(buf.finish())
+}
+
+#[derive(Clone)]
+pub struct ChapterPage {
+    pub(crate) url: String,
+    // As the novel counts it, for errors, unknown to a page fetched on its own
+    pub(crate) number: Option<u64>,
+    page: Html,
+    site: &'static dyn SiteAdapter,
+    // The body as fetched, kept for --keep-raw-html
+    pub(crate) raw: String,
+    // Which of the content selectors matched, looked up on the first `content` call
+    content_selector: OnceCell<usize>,
+}
+
+impl ChapterPage {
+    pub fn new(url: &str, page: Html, raw: String, site: &'static dyn SiteAdapter) -> Self {
+        Self { url: url.to_string(), number: None, page, raw, site, content_selector: OnceCell::new() }
+    }
+
+    fn select(&self, selector_path: &str) -> Result<ElementRef<'_>, NovelError> {
+        select_first(&self.page, selector_path).map_err(|e| self.missing(e))
+    }
+
+    fn missing(&self, error: NovelError) -> NovelError {
+        let name = match self.number {
+            Some(number) => format!("chapter {number} '{}'", self.url),
+            None => format!("'{}'", self.url),
+        };
+        on_page(error, &self.page, name)
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn title(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let selector_path = self.site.chapter_title_selector();
+
+        let title = self.select(selector_path)?.text().collect::<String>();
+        Ok(sanitize_filename(&title))
+    }
+
+    /// The volume the title puts the chapter in, see `title_volume`
+    pub fn volume(&self) -> Option<String> {
+        title_volume(&self.title().ok()?)
+    }
+
+    // The first element with any text matched by the content selectors, tried in order.
+    // A fallback one is reported once per page, however often the content is asked for
+    fn content_element(&self) -> Result<Option<ElementRef<'_>>, NovelError> {
+        let selectors = self.site.content_selectors();
+        if let Some(&i) = self.content_selector.get() {
+            return Ok(self.page.select(&parse_selector(selectors[i])?).next());
+        }
+        for (i, selector_path) in selectors.iter().enumerate() {
+            let selector = parse_selector(selector_path)?;
+            let Some(element) = self.page.select(&selector).next() else {
+                continue;
+            };
+            if element.text().all(|text| text.trim().is_empty()) {
+                continue;
+            }
+            Event::Debug { message: &format!("'{selector_path}' matched the content") }.emit();
+            if i > 0 {
+                Event::Notice { message: &format!("Content found by the fallback selector '{selector_path}'") }.emit();
+            }
+            let _ = self.content_selector.set(i);
+            return Ok(Some(element));
+        }
+        Ok(None)
+    }
+
+    pub fn content(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let Some(element) = self.content_element()? else {
+            let path = self.site.content_selectors().join(", ");
+            return Err(Box::new(self.missing(NovelError::Selector { path, page: None })));
+        };
+        let content = element.inner_html().as_str().trim().to_string();
+        let content = Self::collapse_blank_paragraphs(&remove_ads(&content));
+        Self::mark_scene_breaks(content)
+    }
+
+    /// The next chapter's url, `chapter_next_url` resolved against the one of this chapter
+    pub fn next_url(&self) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(resolve_url(&self.url, &self.chapter_next_url()?)?)
+    }
+
+    pub fn chapter_next_url(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let selector_path = self.site.next_chapter_selector();
+        let attr_name = "href";
+
+        let element = self.select(selector_path)?;
+        if element.value().attr(attr_name).is_none() {
+            return Err(Box::new(NovelError::Attr(attr_name.to_string())));
+        }
+        let chapter_url = Self::enabled_link(element)
+            .ok_or(Box::new(NovelError::Other(format!("The next link of '{}' is disabled", self.url))))?;
+        // The last chapter of some novels links to itself
+        if resolve_url(&self.url, &chapter_url).is_ok_and(|url| canonicalize_url(&url) == canonicalize_url(&self.url)) {
+            return Err(Box::new(NovelError::Other(format!("The next link of '{}' points back at it", self.url))));
+        }
+        Ok(chapter_url)
+    }
+
+    /// The next link, unless the button is disabled or goes nowhere on the last chapter
+    fn enabled_link(element: ElementRef) -> Option<String> {
+        if element.value().classes().any(|class| class == "isDisabled") {
+            return None;
+        }
+        let href = element.value().attr("href")?.trim();
+        (!href.is_empty() && href != "#" && !href.starts_with("javascript:")).then(|| href.to_string())
+    }
+
+    /// None on the first chapter, where the previous button is missing or disabled
+    pub fn chapter_prev_url(&self) -> Option<String> {
+        let selector = parse_selector(self.site.prev_chapter_selector()).ok()?;
+        Self::enabled_link(self.page.select(&selector).next()?)
+    }
+
+    /// The chapter content without any markup, whitespace collapsed
+    pub fn text(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let fragment = Html::parse_fragment(&self.content()?);
+        let text = fragment.root_element().text().collect::<Vec<_>>().join(" ");
+        Ok(text.split_whitespace().collect::<Vec<_>>().join(" "))
+    }
+
+    // Runs of empty paragraphs, "<p></p>" or "<p>&nbsp;</p>", become one,
+    // a single one is left alone as it may be intended spacing
+    fn collapse_blank_paragraphs(html: &str) -> String {
+        fn is_blank(element: ElementRef) -> bool {
+            element.value().name() == "p"
+                && element.descendants().all(|node| match node.value() {
+                    Node::Text(text) => text.trim_matches(|c: char| c.is_whitespace() || c == '\u{a0}').is_empty(),
+                    Node::Element(child) => child.name() == "p" || child.name() == "br",
+                    _ => true,
+                })
+        }
+        let mut fragment = Html::parse_fragment(html);
+        let repeated: Vec<_> = fragment
+            .root_element()
+            .descendants()
+            .filter(|node| ElementRef::wrap(*node).is_some_and(is_blank))
+            .filter(|node| {
+                node.prev_siblings()
+                    .find(|sibling| !matches!(sibling.value(), Node::Text(text) if text.trim().is_empty()))
+                    .and_then(ElementRef::wrap)
+                    .is_some_and(is_blank)
+            })
+            .map(|node| node.id())
+            .collect();
+        for id in repeated {
+            if let Some(mut node) = fragment.tree.get_mut(id) {
+                node.detach();
+            }
+        }
+        fragment.root_element().inner_html()
+    }
+
+    // Scene breaks come as <hr> or as a paragraph of asterisks or ornaments like "* * *" and "◇◇◇",
+    // both end up as the same self-closed rule so they render alike
+    fn mark_scene_breaks(text: String) -> Result<String, Box<dyn std::error::Error>> {
+        let hr = Regex::new(r#"(?i)<hr[^>]*>"#)?;
+        let ornament = Regex::new(r#"<p[^>]*>\s*(?:[*~#=◇◆○●☆★♦♢—–-]\s*){3,}</p>"#)?;
+        let text = hr.replace_all(&text, r#"<hr class="scene-break" />"#);
+        Ok(ornament.replace_all(&text, r#"<hr class="scene-break" />"#).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chapter(body: &str) -> ChapterPage {
+        let html = format!("<html><head></head><body>{body}</body></html>");
+        let url = "https://www.lightnovelworld.com/novel/n/chapter-1";
+        ChapterPage::new(url, Html::parse_document(&html), html, &LightNovelWorldAdapter)
+    }
+
+    #[test]
+    fn scene_breaks_survive_in_both_forms() {
+        let page = chapter(
+            r#"<div class="chapter-content"><p>a</p><hr><p>b</p><p>* * *</p><p>c</p><p style="text-align:center">◇◇◇</p><p>d</p></div>"#,
+        );
+        let content = page.content().unwrap();
+        assert_eq!(content.matches(r#"<hr class="scene-break" />"#).count(), 3);
+        assert!(!content.contains("* * *") && !content.contains("◇◇◇"));
+        assert!(content.contains("<p>d</p>"));
+    }
+
+    #[test]
+    fn runs_of_blank_paragraphs_collapse_to_one() {
+        let html = "<p>a</p><p></p><p>&nbsp;</p> <p><br></p><p>b</p>";
+        assert_eq!(ChapterPage::collapse_blank_paragraphs(html), "<p>a</p><p></p> <p>b</p>");
+    }
+
+    #[test]
+    fn a_single_blank_paragraph_is_kept() {
+        let html = "<p>a</p><p>&nbsp;</p><p>b</p><p></p>";
+        assert_eq!(ChapterPage::collapse_blank_paragraphs(html), html);
+    }
+
+    #[test]
+    fn content_falls_back_to_article() {
+        let page = chapter(r#"<nav>Prev Next</nav><article><p>Paragraph one.</p><p>Two.</p></article>"#);
+        assert_eq!(page.content().unwrap(), "<p>Paragraph one.</p><p>Two.</p>");
+        // Looked up once, a second call goes straight to the selector that matched
+        assert_eq!(page.content_selector.get(), CONTENT_SELECTORS.iter().position(|path| *path == "article").as_ref());
+        assert_eq!(page.content().unwrap(), "<p>Paragraph one.</p><p>Two.</p>");
+    }
+
+    #[test]
+    fn content_prefers_the_chapter_section_over_article() {
+        let page = chapter(r#"<article><section class="chapter-content"><p>Text.</p></section><nav>Next</nav></article>"#);
+        assert_eq!(page.content().unwrap(), "<p>Text.</p>");
+    }
+
+    #[test]
+    fn chapter_xhtml_has_the_title_in_its_head() {
+        let xhtml = compose_chapter_xhtml("Chapter 1 The Test & More", "<p>Text.</p>").unwrap();
+        let head = &xhtml[xhtml.find("<head>").unwrap()..xhtml.find("</head>").unwrap()];
+        assert!(head.contains("<title>Chapter 1 The Test &amp; More</title>"), "{head}");
+        assert!(check_xml(&xhtml).is_ok());
+    }
+
+    #[test]
+    fn remove_ads_drops_nested_ad_divs_whole() {
+        let html = r#"<p>a</p><div class="ad-slot"><div id="x"><div><script>var a = 1;</script>Buy now</div></div></div><p>b</p>"#;
+        assert_eq!(remove_ads(html), "<p>a</p><p>b</p>");
+    }
+
+    #[test]
+    fn remove_ads_keeps_content_in_nested_divs() {
+        let html = r#"<div class="note"><div><p>Kept <b>nested</b> text.</p></div></div><div class="header-line"><p>Header</p></div>"#;
+        assert_eq!(remove_ads(html), html);
+    }
+
+    #[test]
+    fn remove_ads_keeps_rules_and_images_in_divs() {
+        let html = r#"<p>a</p><div class="separator"><hr></div><p>b</p><div><div><img src="map.png"></div></div>"#;
+        assert_eq!(remove_ads(html), html);
+        let page = chapter(&format!(r#"<div class="chapter-content">{html}</div>"#));
+        assert!(page.content().unwrap().contains(r#"<div class="separator"><hr class="scene-break" /></div>"#));
+    }
+
+    #[test]
+    fn remove_ads_drops_empty_divs_and_ad_elements() {
+        let html = r#"<p>a</p><div> <div></div> </div><ins class="adsbygoogle"></ins><iframe src="x"></iframe><p>b</p>"#;
+        assert_eq!(remove_ads(html), "<p>a</p><p>b</p>");
+    }
+
+    #[test]
+    fn a_next_link_to_the_chapter_itself_is_no_next_link() {
+        let page = chapter(r#"<div class="chapter-content"><p>a</p></div><a class="button nextchap" href="chapter-1#top">Next</a>"#);
+        assert!(page.chapter_next_url().is_err());
+        let page = chapter(r#"<div class="chapter-content"><p>a</p></div><a class="button nextchap" href="chapter-2">Next</a>"#);
+        assert_eq!(page.chapter_next_url().unwrap(), "chapter-2");
+    }
+
+    #[test]
+    fn placeholders_are_told_from_pages_without_content() {
+        assert!(is_placeholder(&chapter(r#"<div class="chapter-content"><p>Coming soon!</p></div>"#)).unwrap());
+        assert!(!is_placeholder(&chapter(r#"<div class="chapter-content"><p>It begins.</p></div>"#)).unwrap());
+        assert!(is_placeholder(&chapter("<p>Layout changed</p>")).is_err());
+    }
+
+    // Only the required selectors, like a newly added site
+    struct MinimalAdapter;
+
+    impl SiteAdapter for MinimalAdapter {
+        fn title_selector(&self) -> &str {
+            "h1"
+        }
+        fn author_selector(&self) -> &str {
+            ".author"
+        }
+        fn cover_selector(&self) -> &str {
+            "img"
+        }
+        fn first_chapter_selectors(&self) -> &[&str] {
+            &["a"]
+        }
+        fn chapter_title_selector(&self) -> &str {
+            "h2"
+        }
+        fn content_selectors(&self) -> &[&str] {
+            &["article"]
+        }
+        fn next_chapter_selector(&self) -> &str {
+            "a.next"
+        }
+        fn prev_chapter_selector(&self) -> &str {
+            "a.prev"
+        }
+    }
+
+    #[test]
+    fn cover_details_come_from_the_site_adapter() {
+        let html = r#"<html><body><h1 class="novel-title">Novel</h1><h2 class="alternative-title">Alt</h2>
+            <div class="header-stats"><span><strong>12</strong> Chapters</span><span>Ongoing</span></div>
+            <div class="updinfo"><time datetime="2024-02-03T10:00:00Z">2 days ago</time></div>
+            <div class="summary"><div class="content"><p>One.</p><p>Two.</p></div></div></body></html>"#;
+        let url = "https://www.lightnovelworld.com/novel/n";
+        let cover = CoverPage::new(url, Html::parse_document(html), &LightNovelWorldAdapter);
+        assert_eq!(cover.alt_title().as_deref(), Some("Alt"));
+        assert_eq!(cover.chapter_count(), Some(12));
+        assert_eq!(cover.status().as_deref(), Some("Ongoing"));
+        assert_eq!(cover.updated_date().unwrap(), "2024-02-03");
+        assert_eq!(cover.description().as_deref(), Some("One.\nTwo."));
+
+        let cover = CoverPage::new(url, Html::parse_document(html), &MinimalAdapter);
+        assert_eq!((cover.alt_title(), cover.description(), cover.status()), (None, None, None));
+        assert_eq!(cover.chapter_count(), None);
+        assert!(cover.updated_date().is_err());
+    }
+
+    #[test]
+    fn chapter_list_is_under_the_novel_url() {
+        let list = LightNovelWorldAdapter.chapter_list("https://www.lightnovelworld.com/novel/n/?ref=home#top");
+        assert_eq!(list, Some(("https://www.lightnovelworld.com/novel/n/chapters".to_string(), "ul.chapter-list li a")));
+        assert_eq!(MinimalAdapter.chapter_list("https://example.com/novel/n"), None);
+    }
+
+    #[test]
+    fn cover_title_and_author_are_plain_text() {
+        let html = r#"<html><body><h1>Tom &amp; Jerry</h1><p class="author">A&nbsp;<b>B</b></p></body></html>"#;
+        let cover = CoverPage::new("https://example.com/novel/n", Html::parse_document(html), &MinimalAdapter);
+        assert_eq!(cover.title().unwrap(), "Tom & Jerry");
+        assert_eq!(cover.author().unwrap(), "A\u{a0}B");
+    }
+}